@@ -0,0 +1,62 @@
+//! Runs a command table on a Linux host
+//!
+//! `cargo run --example hosted --features pty` runs it on the terminal, and
+//! adding `-- --pty` runs it on a pseudo-terminal to connect to with e.g.
+//! `screen`, as a board's serial port would be. Ctrl+D ends the session.
+
+use embedded_cli as cli;
+
+use cli::{ArgKind, ArgSpec, Args, Cli, CommandProcessorError, Pty, ReturnCode};
+use heapless::String;
+
+const LED_ARGS: &[ArgSpec<'static>] = &[ArgSpec::required("state", ArgKind::Enum(&["on", "off"]))];
+
+fn hello<'a>(
+    writer: Option<&mut (dyn core::fmt::Write + 'a)>,
+) -> Result<ReturnCode, CommandProcessorError> {
+    if let Some(writer) = writer {
+        write!(writer, "Hello").map_err(|_| CommandProcessorError::WriteError)?;
+    }
+    Ok(ReturnCode::Success)
+}
+
+fn led<'a>(
+    args: &Args<'_>,
+    writer: Option<&mut (dyn core::fmt::Write + 'a)>,
+) -> Result<ReturnCode, CommandProcessorError> {
+    if let Some(writer) = writer {
+        write!(writer, "LED {}", args.get(0).unwrap_or(""))
+            .map_err(|_| CommandProcessorError::WriteError)?;
+    }
+    Ok(ReturnCode::Success)
+}
+
+fn main() {
+    let mut cli = Cli::<8, 32>::new();
+
+    cli.add_command(
+        String::from("hello"),
+        hello,
+        Some(String::from("Say hello")),
+    )
+    .unwrap();
+    cli.add_command_with_schema(
+        String::from("led"),
+        led,
+        LED_ARGS,
+        Some(String::from("Turn the LED on or off")),
+    )
+    .unwrap();
+
+    let result = if std::env::args().any(|arg| arg == "--pty") {
+        let pty = Pty::open().expect("no pseudo-terminal");
+        println!("Connect to {}", pty.name());
+        cli.run_std(&pty, &pty)
+    } else {
+        cli.run_stdio()
+    };
+
+    if let Err(error) = result {
+        eprintln!("\r\n{:?}", error);
+    }
+}
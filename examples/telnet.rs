@@ -0,0 +1,57 @@
+//! Serves a command table over telnet on a Linux host
+//!
+//! `cargo run --example telnet --features std,telnet` listens on
+//! 127.0.0.1:2323 for `telnet localhost 2323`. Another address can be given
+//! after `--`, such as `0.0.0.0:2323` to let other hosts connect. One client
+//! is served at a time, and Ctrl+D ends its session.
+
+use std::net::TcpListener;
+
+use embedded_cli as cli;
+
+use cli::{Cli, CommandProcessorError, ReturnCode, TelnetReader, TelnetWriter};
+use heapless::String;
+
+fn hello<'a>(
+    writer: Option<&mut (dyn core::fmt::Write + 'a)>,
+) -> Result<ReturnCode, CommandProcessorError> {
+    if let Some(writer) = writer {
+        write!(writer, "Hello").map_err(|_| CommandProcessorError::WriteError)?;
+    }
+    Ok(ReturnCode::Success)
+}
+
+fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:2323".into());
+    let listener = TcpListener::bind(&address).unwrap();
+
+    let mut cli = Cli::<8, 32>::new();
+    cli.add_command(
+        String::from("hello"),
+        hello,
+        Some(String::from("Say hello")),
+    )
+    .unwrap();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+
+        // Nothing typed by the last client is left for this one
+        cli.reset();
+        let result = TelnetReader::new(&stream)
+            .map_err(cli::CliError::ReadError)
+            .and_then(|reader| cli.run_std(reader, TelnetWriter::new(&stream)));
+
+        if let Err(error) = result {
+            eprintln!("{:?}", error);
+        }
+    }
+}
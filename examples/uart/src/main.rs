@@ -11,8 +11,6 @@ use panic_probe as _;
 
 use heapless::String;
 
-//mod serial_setup;
-
 use fugit::RateExtU32;
 
 // Provide an alias for our BSP so we can switch targets quickly.
@@ -76,12 +74,13 @@ fn main() -> ! {
     );
 
     // Make a UART on the given pins
-    let mut uart = bsp::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+    let uart = bsp::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
         .enable(
             UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
             clocks.peripheral_clock.freq(),
         )
         .unwrap();
+    let (mut rx, mut tx) = uart.split();
 
     let mut cli = Cli::<8, 32>::new();
 
@@ -96,7 +95,7 @@ fn main() -> ! {
         Err(_) => info!("Error adding command"),
     };
 
-    match cli.init(&mut uart) {
+    match cli.init_split(&mut tx) {
         Ok(()) => (),
         Err(_) => info!("Error initializing CLI"),
     }
@@ -104,7 +103,9 @@ fn main() -> ! {
     info!("Entering loop");
     loop {
         // Check for new data
-        cli.run(&mut uart);
+        if cli.run_split(&mut rx, &mut tx).is_err() {
+            info!("Error running CLI");
+        }
     }
 }
 
@@ -17,8 +17,6 @@ use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
 use heapless::String;
 
-mod serial_setup;
-
 // Provide an alias for our BSP so we can switch targets quickly.
 // Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
 use rp_pico as bsp;
@@ -68,14 +66,15 @@ fn main() -> ! {
     ));
 
     // Set up the USB Communications Class Device driver
-    let binding = SerialPort::new(&usb_bus);
-    let mut serial = serial_setup::WritableSerialPort::new(binding);
+    let mut serial = SerialPort::new(&usb_bus);
 
     // Create a USB device with a fake VID and PID
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
-        .manufacturer("Fake company")
-        .product("Serial port")
-        .serial_number("TEST")
+        .strings(&[StringDescriptors::default()
+            .manufacturer("Fake company")
+            .product("Serial port")
+            .serial_number("TEST")])
+        .unwrap()
         .device_class(USB_CLASS_CDC) // from: https://www.usb.org/defined-class-codes
         .build();
 
@@ -92,7 +91,7 @@ fn main() -> ! {
         Err(_) => info!("Error adding command"),
     };
 
-    match cli.init(&mut serial) {
+    match cli.init_io(&mut serial) {
         Ok(()) => (),
         Err(_) => info!("Error initializing CLI"),
     }
@@ -100,7 +99,9 @@ fn main() -> ! {
     info!("Entering loop");
     loop {
         if usb_dev.poll(&mut [&mut serial]) {
-            cli.run(&mut serial);
+            if cli.run_io(&mut serial).is_err() {
+                info!("Error running CLI");
+            }
         }
     }
 }
@@ -0,0 +1,291 @@
+use core::fmt;
+
+use heapless::{String, Vec};
+
+use crate::schema::{ArgSpec, FromArg, Matches, OptionName};
+
+/// Maximum number of tokens (command name included) a line can be split into
+pub const MAX_TOKENS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizeError {
+    UnterminatedQuote,
+    TooManyTokens,
+    LineTooLong,
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizeError::UnterminatedQuote => write!(f, "unterminated quote"),
+            TokenizeError::TooManyTokens => write!(f, "too many arguments"),
+            TokenizeError::LineTooLong => write!(f, "line too long"),
+        }
+    }
+}
+
+/// A line split into whitespace separated tokens.
+///
+/// Quoting follows the shell: text inside single quotes is taken literally,
+/// inside double quotes a backslash escapes `"` and `\`, and outside of
+/// quotes a backslash escapes any character.
+pub(crate) struct Tokens<const N: usize> {
+    buffer: String<N>,
+    ranges: Vec<(usize, usize), MAX_TOKENS>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+impl<const N: usize> Tokens<N> {
+    pub(crate) fn parse(line: &str) -> Result<Tokens<N>, TokenizeError> {
+        let mut tokens = Tokens {
+            buffer: String::new(),
+            ranges: Vec::new(),
+        };
+
+        let mut quote = Quote::None;
+        let mut in_token = false;
+        let mut start = 0;
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let literal = match (quote, c) {
+                (Quote::None, ' ' | '\t') => {
+                    if in_token {
+                        tokens.end_token(start)?;
+                        in_token = false;
+                    }
+                    continue;
+                }
+                (Quote::None, '\'') => {
+                    quote = Quote::Single;
+                    None
+                }
+                (Quote::None, '"') => {
+                    quote = Quote::Double;
+                    None
+                }
+                (Quote::None, '\\') => Some(chars.next().unwrap_or('\\')),
+                (Quote::Single, '\'') | (Quote::Double, '"') => {
+                    quote = Quote::None;
+                    None
+                }
+                (Quote::Double, '\\') => match chars.peek() {
+                    Some(&next) if next == '"' || next == '\\' => chars.next(),
+                    _ => Some('\\'),
+                },
+                (_, c) => Some(c),
+            };
+
+            if !in_token {
+                in_token = true;
+                start = tokens.buffer.len();
+            }
+
+            if let Some(c) = literal {
+                tokens
+                    .buffer
+                    .push(c)
+                    .map_err(|_| TokenizeError::LineTooLong)?;
+            }
+        }
+
+        if quote != Quote::None {
+            return Err(TokenizeError::UnterminatedQuote);
+        }
+
+        if in_token {
+            tokens.end_token(start)?;
+        }
+
+        Ok(tokens)
+    }
+
+    fn end_token(&mut self, start: usize) -> Result<(), TokenizeError> {
+        self.ranges
+            .push((start, self.buffer.len()))
+            .map_err(|_| TokenizeError::TooManyTokens)
+    }
+
+    /// The tokens from `first` onwards
+    pub(crate) fn args(&self, first: usize) -> Args<'_> {
+        Args {
+            buffer: &self.buffer,
+            ranges: self.ranges.get(first..).unwrap_or(&[]),
+            options: &[],
+            schema: &[],
+        }
+    }
+}
+
+/// The arguments following the command name on the line.
+///
+/// For commands with a schema, flags and options are taken out of the
+/// positional arguments and read with [`Args::has_flag`] and [`Args::opt`].
+#[derive(Clone, Copy)]
+pub struct Args<'l> {
+    buffer: &'l str,
+    ranges: &'l [(usize, usize)],
+    options: &'l [(usize, Option<(usize, usize)>)],
+    schema: &'l [ArgSpec<'l>],
+}
+
+impl<'l> Args<'l> {
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'l str> {
+        let buffer = self.buffer;
+        self.ranges
+            .get(index)
+            .map(|&(start, end)| &buffer[start..end])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'l str> + 'l {
+        let buffer = self.buffer;
+        self.ranges
+            .iter()
+            .map(move |&(start, end)| &buffer[start..end])
+    }
+
+    /// Converts the argument at `index`, e.g. `args.parse::<u32>(0)`
+    pub fn parse<T: FromArg<'l>>(&self, index: usize) -> Option<T> {
+        self.get(index).and_then(T::from_arg)
+    }
+
+    /// Converts the argument declared as `name` in the command's schema
+    pub fn value<T: FromArg<'l>>(&self, name: &str) -> Option<T> {
+        let index = self
+            .schema
+            .iter()
+            .filter(|spec| spec.is_positional())
+            .position(|spec| spec.name == name)?;
+        self.parse(index)
+    }
+
+    /// Whether a flag was given, looked up by its short `char` or long name
+    pub fn has_flag(&self, name: impl OptionName) -> bool {
+        self.options
+            .iter()
+            .any(|&(position, _)| name.matches(&self.schema[position]))
+    }
+
+    /// Converts the value of an option, e.g. `args.opt::<u16>("count")`.
+    /// If the option was given more than once the last value wins.
+    pub fn opt<T: FromArg<'l>>(&self, name: impl OptionName) -> Option<T> {
+        let (_, value) = self
+            .options
+            .iter()
+            .rev()
+            .find(|&&(position, _)| name.matches(&self.schema[position]))?;
+        value.and_then(|range| T::from_arg(self.slice(range)))
+    }
+
+    pub(crate) fn range(&self, index: usize) -> (usize, usize) {
+        self.ranges[index]
+    }
+
+    pub(crate) fn slice(&self, (start, end): (usize, usize)) -> &'l str {
+        &self.buffer[start..end]
+    }
+
+    /// The positional arguments and options left after matching against
+    /// `schema`
+    pub(crate) fn with_matches(self, schema: &'l [ArgSpec<'l>], matches: &'l Matches) -> Args<'l> {
+        Args {
+            buffer: self.buffer,
+            ranges: &matches.positional,
+            options: &matches.options,
+            schema,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> std::vec::Vec<std::string::String> {
+        let tokens = Tokens::<64>::parse(line).unwrap();
+        tokens.args(0).iter().map(|token| token.into()).collect()
+    }
+
+    #[test]
+    fn test_whitespace() {
+        assert_eq!(tokens("led 3  on"), ["led", "3", "on"]);
+        assert_eq!(tokens("  \tled\t3 "), ["led", "3"]);
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(tokens("say \"hello world\""), ["say", "hello world"]);
+        assert_eq!(tokens("say 'a \"b\" c'"), ["say", "a \"b\" c"]);
+        assert_eq!(tokens("say a\"b c\"d"), ["say", "ab cd"]);
+        assert_eq!(tokens("say \"\" ''"), ["say", "", ""]);
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(tokens("say hello\\ world"), ["say", "hello world"]);
+        assert_eq!(tokens("say \"a\\\"b\\\\c\\d\""), ["say", "a\"b\\c\\d"]);
+        assert_eq!(tokens("say 'a\\b'"), ["say", "a\\b"]);
+        assert_eq!(tokens("say a\\"), ["say", "a\\"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Tokens::<64>::parse("say \"hello").err(),
+            Some(TokenizeError::UnterminatedQuote)
+        );
+        assert_eq!(
+            Tokens::<64>::parse("a b c d e f g h i j k l m n o p q").err(),
+            Some(TokenizeError::TooManyTokens)
+        );
+    }
+
+    #[test]
+    fn test_args() {
+        let tokens = Tokens::<64>::parse("pwm 1 500").unwrap();
+        let args = tokens.args(1);
+
+        assert_eq!(args.len(), 2);
+        assert_eq!(args.get(0), Some("1"));
+        assert_eq!(args.get(1), Some("500"));
+        assert_eq!(args.get(2), None);
+        assert!(args.iter().eq(["1", "500"]));
+        assert!(tokens.args(3).is_empty());
+    }
+
+    #[test]
+    fn test_typed_args() {
+        use crate::schema::ArgKind;
+
+        let schema = [
+            ArgSpec::required("addr", ArgKind::U8),
+            ArgSpec::required("on", ArgKind::Bool),
+        ];
+        let tokens = Tokens::<64>::parse("i2c 0x48 off").unwrap();
+        let matches = crate::schema::match_args(&schema, &tokens.args(1))
+            .ok()
+            .unwrap();
+        let args = tokens.args(1).with_matches(&schema, &matches);
+
+        assert_eq!(args.parse::<u8>(0), Some(0x48));
+        assert_eq!(args.value::<u32>("addr"), Some(0x48));
+        assert_eq!(args.value::<bool>("on"), Some(false));
+        assert_eq!(args.value::<&str>("on"), Some("off"));
+        assert_eq!(args.value::<u8>("missing"), None);
+    }
+}
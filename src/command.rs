@@ -0,0 +1,373 @@
+use heapless::{String, Vec};
+
+use crate::args::Args;
+use crate::complete::CompletionHook;
+use crate::schema::ArgSpec;
+use crate::CliError;
+
+/// What a command reports when it has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnCode {
+    Success,
+    /// The command ran but did not do what was asked
+    Failure,
+}
+
+/// Errors a command can fail with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandProcessorError {
+    /// Writing the command's output failed
+    WriteError,
+}
+
+/// What a command callback returns
+pub type CommandCallbackReturn<'a> = Result<ReturnCode, CommandProcessorError>;
+
+/// Callback for a command. The writer is only borrowed for the call, so the
+/// output can go to a sink that lives no longer than the call to
+/// [`Cli::feed`](crate::Cli::feed).
+pub type CommandCallback<'a> = fn(Option<&mut dyn core::fmt::Write>) -> CommandCallbackReturn<'a>;
+
+/// Callback for a command that takes arguments
+pub type ArgsCommandCallback<'a> =
+    fn(&Args<'_>, Option<&mut dyn core::fmt::Write>) -> CommandCallbackReturn<'a>;
+
+/// Callback handed the context passed to
+/// [`Cli::run_with_context`](crate::Cli::run_with_context)
+pub type ContextCommandCallback<'a, Ctx> =
+    fn(&mut Ctx, &Args<'_>, Option<&mut dyn core::fmt::Write>) -> CommandCallbackReturn<'a>;
+
+/// Closure handed the context passed to
+/// [`Cli::run_with_context`](crate::Cli::run_with_context). The `Cli` only
+/// borrows the closure, so no allocator is needed.
+pub type CommandClosure<'a, Ctx> = dyn FnMut(&mut Ctx, &Args<'_>, Option<&mut dyn core::fmt::Write>) -> CommandCallbackReturn<'a>
+    + 'a;
+
+/// Commands that run asynchronously, such as ones waiting on a peripheral.
+/// They are added with [`Cli::add_async_command`](crate::Cli::add_async_command)
+/// and run by the implementation handed to
+/// [`Cli::run_async_with`](crate::Cli::run_async_with), which can hold
+/// their state.
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncCommands<Ctx = ()> {
+    /// Runs the command added as `id`, writing its output to `out`
+    async fn call<W: embedded_io_async::Write>(
+        &mut self,
+        id: usize,
+        context: &mut Ctx,
+        args: &Args<'_>,
+        out: &mut W,
+    ) -> CommandCallbackReturn<'static>;
+}
+
+/// No async commands, for [`Cli::run_async`](crate::Cli::run_async)
+#[cfg(feature = "async")]
+impl<Ctx> AsyncCommands<Ctx> for () {
+    async fn call<W: embedded_io_async::Write>(
+        &mut self,
+        _id: usize,
+        _context: &mut Ctx,
+        _args: &Args<'_>,
+        _out: &mut W,
+    ) -> CommandCallbackReturn<'static> {
+        Ok(ReturnCode::Success)
+    }
+}
+
+pub(crate) enum Callback<'a, Ctx> {
+    Simple(CommandCallback<'a>),
+    WithArgs(ArgsCommandCallback<'a>),
+    WithContext(ContextCommandCallback<'a, Ctx>),
+    Closure(&'a mut CommandClosure<'a, Ctx>),
+    /// Run by [`AsyncCommands::call`] with this id
+    #[cfg(feature = "async")]
+    Async(usize),
+}
+
+impl<'a, Ctx> Callback<'a, Ctx> {
+    pub(crate) fn call(
+        &mut self,
+        context: &mut Ctx,
+        args: &Args<'_>,
+        writer: Option<&mut dyn core::fmt::Write>,
+    ) -> Result<ReturnCode, CliError> {
+        let result = match self {
+            Callback::Simple(callback) => callback(writer),
+            Callback::WithArgs(callback) => callback(args, writer),
+            Callback::WithContext(callback) => callback(context, args, writer),
+            Callback::Closure(closure) => closure(context, args, writer),
+            // Only `run_async_with_context` can await them
+            #[cfg(feature = "async")]
+            Callback::Async(_) => return Err(CliError::AsyncCommand),
+        };
+
+        result.map_err(CliError::CommandProcessorError)
+    }
+}
+
+pub(crate) struct Command<'a, const HELP_STR_SIZE: usize, Ctx> {
+    pub(crate) name: String<32>,
+    pub(crate) help: Option<String<HELP_STR_SIZE>>,
+    /// `None` for a group, which only holds subcommands
+    pub(crate) callback: Option<Callback<'a, Ctx>>,
+    pub(crate) schema: Option<&'a [ArgSpec<'a>]>,
+    pub(crate) completion: Option<CompletionHook>,
+    /// Index of the group the command belongs to, `None` at the top level
+    pub(crate) parent: Option<usize>,
+}
+
+impl<'a, const HELP_STR_SIZE: usize, Ctx> Command<'a, HELP_STR_SIZE, Ctx> {
+    pub(crate) fn is_group(&self) -> bool {
+        self.callback.is_none()
+    }
+}
+
+/// How far a line got into the command tree
+pub(crate) struct Resolved {
+    /// The deepest command or group matched, `None` if the first word is
+    /// not a top level command
+    pub(crate) index: Option<usize>,
+    /// Number of words making up the command's path
+    pub(crate) depth: usize,
+}
+
+/// The registered commands. Subcommands are kept in the same flat table,
+/// pointing at their group by index; a group always comes before its
+/// subcommands.
+pub(crate) struct CommandTable<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize, Ctx> {
+    commands: Vec<Command<'a, HELP_STR_SIZE, Ctx>, NUM_COMMANDS>,
+}
+
+impl<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize, Ctx>
+    CommandTable<'a, NUM_COMMANDS, HELP_STR_SIZE, Ctx>
+{
+    pub(crate) fn new() -> CommandTable<'a, NUM_COMMANDS, HELP_STR_SIZE, Ctx> {
+        CommandTable {
+            commands: Vec::new(),
+        }
+    }
+
+    /// Adds a command or group under `path`, e.g. `"wifi scan"` adds `scan`
+    /// to the `wifi` group, which must already exist
+    pub(crate) fn add(
+        &mut self,
+        path: &str,
+        callback: Option<Callback<'a, Ctx>>,
+        schema: Option<&'a [ArgSpec<'a>]>,
+        help: Option<String<HELP_STR_SIZE>>,
+    ) -> Result<(), CliError> {
+        let (group, name) = match path.trim_end().rsplit_once(char::is_whitespace) {
+            Some((group, name)) => (Some(group), name),
+            None => (None, path.trim()),
+        };
+
+        let parent = match group {
+            Some(group) => match self.lookup(group) {
+                Some(parent) if self.commands[parent].is_group() => Some(parent),
+                _ => return Err(CliError::UnknownCommand),
+            },
+            None => None,
+        };
+
+        if name.is_empty() {
+            return Err(CliError::UnknownCommand);
+        }
+
+        if self.find(parent, name).is_some() {
+            return Err(CliError::DuplicateCommand);
+        }
+
+        let mut segment = String::new();
+        segment
+            .push_str(name)
+            .map_err(|_| CliError::CommandBufferError)?;
+
+        self.commands
+            .push(Command {
+                name: segment,
+                help,
+                callback,
+                schema,
+                completion: None,
+                parent,
+            })
+            .map_err(|_| CliError::CommandTableFull)
+    }
+
+    /// Removes a command, or a group along with all of its subcommands
+    pub(crate) fn remove(&mut self, path: &str) -> Result<(), CliError> {
+        let target = self.lookup(path).ok_or(CliError::UnknownCommand)?;
+
+        // Groups come before their subcommands, so one pass finds everything
+        // below the target
+        let mut doomed = [false; NUM_COMMANDS];
+        for index in target..self.commands.len() {
+            doomed[index] = index == target
+                || matches!(self.commands[index].parent, Some(parent) if doomed[parent]);
+        }
+
+        for index in (target..self.commands.len()).rev() {
+            if doomed[index] {
+                self.commands.remove(index);
+
+                for command in self.commands.iter_mut() {
+                    match command.parent {
+                        Some(parent) if parent > index => command.parent = Some(parent - 1),
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn find(&self, parent: Option<usize>, name: &str) -> Option<usize> {
+        self.commands
+            .iter()
+            .position(|command| command.parent == parent && command.name == name)
+    }
+
+    /// Finds a command from its space separated path
+    pub(crate) fn lookup(&self, path: &str) -> Option<usize> {
+        let resolved = self.resolve(path.split_whitespace());
+
+        if resolved.depth == path.split_whitespace().count() {
+            resolved.index
+        } else {
+            None
+        }
+    }
+
+    /// Walks down the tree for as long as the words name groups and their
+    /// subcommands
+    pub(crate) fn resolve<'w>(&self, words: impl Iterator<Item = &'w str>) -> Resolved {
+        let mut resolved = Resolved {
+            index: None,
+            depth: 0,
+        };
+
+        for word in words {
+            if let Some(index) = resolved.index {
+                if !self.commands[index].is_group() {
+                    break;
+                }
+            }
+
+            match self.find(resolved.index, word) {
+                Some(index) => {
+                    resolved.index = Some(index);
+                    resolved.depth += 1;
+                }
+                None => break,
+            }
+        }
+
+        resolved
+    }
+
+    pub(crate) fn get(&self, index: usize) -> &Command<'a, HELP_STR_SIZE, Ctx> {
+        &self.commands[index]
+    }
+
+    pub(crate) fn get_mut(&mut self, index: usize) -> &mut Command<'a, HELP_STR_SIZE, Ctx> {
+        &mut self.commands[index]
+    }
+
+    /// The commands directly under `parent`, or the top level commands
+    pub(crate) fn children(
+        &self,
+        parent: Option<usize>,
+    ) -> impl Iterator<Item = &Command<'a, HELP_STR_SIZE, Ctx>> {
+        self.commands
+            .iter()
+            .filter(move |command| command.parent == parent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReturnCode;
+
+    fn table() -> CommandTable<'static, 7, 32, ()> {
+        let callback = || Some(Callback::Simple(|_| Ok(ReturnCode::Success)));
+        let mut table = CommandTable::new();
+
+        table.add("wifi", None, None, None).unwrap();
+        table.add("wifi scan", callback(), None, None).unwrap();
+        table.add("i2c", None, None, None).unwrap();
+        table.add("i2c read", callback(), None, None).unwrap();
+        table.add("wifi  connect", callback(), None, None).unwrap();
+        table.add("reset", callback(), None, None).unwrap();
+
+        table
+    }
+
+    #[test]
+    fn test_add() {
+        let mut table = table();
+        let callback = || Some(Callback::Simple(|_| Ok(ReturnCode::Success)));
+
+        assert!(matches!(
+            table.add("wifi scan", callback(), None, None),
+            Err(CliError::DuplicateCommand)
+        ));
+        assert!(matches!(
+            table.add("reset hard", callback(), None, None),
+            Err(CliError::UnknownCommand)
+        ));
+        assert!(matches!(
+            table.add("cfg get", callback(), None, None),
+            Err(CliError::UnknownCommand)
+        ));
+        assert!(table.add("i2c scan", callback(), None, None).is_ok());
+        assert!(matches!(
+            table.add("i2c write", callback(), None, None),
+            Err(CliError::CommandTableFull)
+        ));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let table = table();
+
+        let resolved = table.resolve("wifi connect home".split_whitespace());
+        assert_eq!(resolved.depth, 2);
+        assert_eq!(table.get(resolved.index.unwrap()).name, "connect");
+
+        let resolved = table.resolve("reset now".split_whitespace());
+        assert_eq!(resolved.depth, 1);
+        assert_eq!(table.get(resolved.index.unwrap()).name, "reset");
+
+        let resolved = table.resolve("wifi".split_whitespace());
+        assert_eq!(resolved.depth, 1);
+        assert!(table.get(resolved.index.unwrap()).is_group());
+
+        assert!(table.resolve("scan".split_whitespace()).index.is_none());
+        assert_eq!(table.lookup("i2c read"), Some(3));
+        assert_eq!(table.lookup("i2c scan"), None);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut table = table();
+
+        assert!(table.remove("wifi").is_ok());
+        assert!(table.lookup("wifi scan").is_none());
+        assert!(table.lookup("wifi connect").is_none());
+        assert_eq!(table.lookup("i2c read"), Some(1));
+        assert!(table
+            .children(None)
+            .map(|c| c.name.as_str())
+            .eq(["i2c", "reset"]));
+
+        assert!(table.remove("i2c read").is_ok());
+        assert_eq!(table.children(Some(0)).count(), 0);
+        assert!(matches!(
+            table.remove("i2c read"),
+            Err(CliError::UnknownCommand)
+        ));
+    }
+}
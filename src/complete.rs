@@ -0,0 +1,184 @@
+use core::fmt::{self, Write};
+
+use heapless::{String, Vec};
+
+use crate::args::Args;
+
+/// Maximum number of candidates kept when completing a word
+pub const MAX_COMPLETIONS: usize = 16;
+
+/// Room for the text of all candidates together
+const BUFFER_SIZE: usize = 256;
+
+/// Width of the terminal assumed when listing candidates in columns
+const TERMINAL_WIDTH: usize = 80;
+
+/// Completion hook for a command's arguments, registered with
+/// [`Cli::add_completion`](crate::Cli::add_completion).
+///
+/// `args` holds the arguments up to the cursor and `index` is the one being
+/// completed, which is `args.len()` when the cursor starts a new argument.
+/// Candidates are handed to [`Completions::push`].
+pub type CompletionHook = fn(args: &Args<'_>, index: usize, completions: &mut Completions<'_>);
+
+/// The candidates for the word being completed. Only those starting with
+/// the part of the word already typed are kept.
+pub struct Completions<'p> {
+    prefix: &'p str,
+    buffer: String<BUFFER_SIZE>,
+    ranges: Vec<(usize, usize), MAX_COMPLETIONS>,
+}
+
+impl<'p> Completions<'p> {
+    pub(crate) fn new(prefix: &'p str) -> Completions<'p> {
+        Completions {
+            prefix,
+            buffer: String::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// The part of the word already typed
+    pub fn prefix(&self) -> &str {
+        self.prefix
+    }
+
+    /// Adds a candidate if it matches, ignoring duplicates. Returns `false`
+    /// once there is no room left.
+    pub fn push(&mut self, candidate: &str) -> bool {
+        if !candidate.starts_with(self.prefix) || self.iter().any(|c| c == candidate) {
+            return true;
+        }
+
+        let start = self.buffer.len();
+        if self.ranges.is_full() || self.buffer.push_str(candidate).is_err() {
+            return false;
+        }
+        let _ = self.ranges.push((start, self.buffer.len()));
+
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.ranges
+            .iter()
+            .map(move |&(start, end)| &self.buffer[start..end])
+    }
+
+    /// The text all candidates start with, at least the prefix typed
+    pub(crate) fn common_prefix(&self) -> &str {
+        let mut candidates = self.iter();
+        let mut common = match candidates.next() {
+            Some(first) => first,
+            None => return self.prefix,
+        };
+
+        for candidate in candidates {
+            let same = common
+                .bytes()
+                .zip(candidate.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            common = &common[..same];
+        }
+
+        common
+    }
+
+    /// Lists the candidates in alphabetical order, in as many columns as fit
+    /// on the terminal, each row on a new line
+    pub(crate) fn write_columns(&mut self, out: &mut dyn Write) -> fmt::Result {
+        let buffer = &self.buffer;
+        self.ranges
+            .sort_unstable_by_key(|&(start, end)| &buffer[start..end]);
+
+        let width = self.iter().map(str::len).max().unwrap_or(0) + 2;
+        let columns = (TERMINAL_WIDTH / width).max(1);
+        let rows = self.len().div_ceil(columns);
+
+        for row in 0..rows {
+            out.write_str("\r\n")?;
+
+            let mut candidates = self.iter().skip(row).step_by(rows).peekable();
+            while let Some(candidate) = candidates.next() {
+                match candidates.peek() {
+                    Some(_) => write!(out, "{:width$}", candidate, width = width)?,
+                    None => out.write_str(candidate)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push() {
+        let mut completions = Completions::new("re");
+
+        assert!(completions.push("reset"));
+        assert!(completions.push("led"));
+        assert!(completions.push("reset"));
+        assert!(completions.push("read"));
+        assert!(completions.iter().eq(["reset", "read"]));
+        assert_eq!(completions.common_prefix(), "re");
+
+        let mut completions = Completions::new("w");
+        for _ in 0..MAX_COMPLETIONS {
+            completions.push("");
+        }
+        assert_eq!(completions.len(), 0);
+        assert_eq!(completions.common_prefix(), "w");
+
+        completions.push("wifi");
+        completions.push("wifi_scan");
+        assert_eq!(completions.common_prefix(), "wifi");
+    }
+
+    #[test]
+    fn test_full() {
+        let mut completions = Completions::new("");
+        let names = [
+            "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "a8", "a9", "b0", "b1", "b2", "b3",
+            "b4", "b5",
+        ];
+
+        assert!(names.iter().all(|name| completions.push(name)));
+        assert!(!completions.push("b6"));
+        assert_eq!(completions.len(), MAX_COMPLETIONS);
+    }
+
+    #[test]
+    fn test_columns() {
+        let mut completions = Completions::new("");
+        for name in ["pwm", "i2c", "led", "reset", "help"] {
+            completions.push(name);
+        }
+
+        let mut out = std::string::String::new();
+        completions.write_columns(&mut out).unwrap();
+        assert_eq!(out, "\r\nhelp   i2c    led    pwm    reset");
+
+        let mut completions = Completions::new("");
+        let long = "a_fairly_long_command_name_xyz";
+        for name in ["b", long, "c"] {
+            completions.push(name);
+        }
+
+        let mut out = std::string::String::new();
+        completions.write_columns(&mut out).unwrap();
+        assert_eq!(out, "\r\na_fairly_long_command_name_xyz  c\r\nb");
+    }
+}
@@ -0,0 +1,206 @@
+use core::fmt;
+
+use heapless::{HistoryBuffer, String};
+
+/// Why a line could not be expanded
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ExpandError<'l> {
+    /// No history entry matches the reference
+    EventNotFound(&'l str),
+    /// The previous line does not contain the text to substitute
+    SubstitutionFailed,
+    /// The expanded line does not fit in the line buffer
+    TooLong,
+}
+
+impl fmt::Display for ExpandError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::EventNotFound(event) => write!(f, "{}: event not found", event),
+            ExpandError::SubstitutionFailed => write!(f, "substitution failed"),
+            ExpandError::TooLong => write!(f, "line too long"),
+        }
+    }
+}
+
+/// Expands references to the history in `line` into `out`, like bash:
+///
+/// - `!!` is the previous line
+/// - `!n` is entry `n`, counting from 1 for the oldest entry kept
+/// - `!-n` is the `n`th line back
+/// - `!prefix` is the most recent line starting with `prefix`
+/// - `^old^new` at the start of the line is the previous line with the
+///   first `old` replaced by `new`
+///
+/// A `!` followed by a space or `=`, or at the end of the line, is left as
+/// it is, as is any `!` inside single quotes or after a backslash. Returns
+/// whether anything was expanded.
+pub(crate) fn expand<'l, const N: usize, const L: usize, const H: usize>(
+    line: &'l str,
+    history: &HistoryBuffer<String<L>, H>,
+    out: &mut String<N>,
+) -> Result<bool, ExpandError<'l>> {
+    out.clear();
+
+    if let Some(substitution) = line.strip_prefix('^') {
+        substitute(substitution, history, out)?;
+        return Ok(true);
+    }
+
+    let mut expanded = false;
+    let mut quoted = false;
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+
+        match c {
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => {
+                if let Some(escaped) = rest.chars().next() {
+                    push(out, '\\')?;
+                    rest = &rest[escaped.len_utf8()..];
+                    push(out, escaped)?;
+                    continue;
+                }
+            }
+            '!' if !quoted && rest.starts_with(|c: char| c != ' ' && c != '=') => {
+                let end = match rest.strip_prefix('!') {
+                    Some(_) => 1,
+                    None => rest.find(' ').unwrap_or(rest.len()),
+                };
+                let event = &rest[..end];
+                let reference = &line[line.len() - rest.len() - 1..line.len() - rest.len() + end];
+
+                let entry = find(event, history).ok_or(ExpandError::EventNotFound(reference))?;
+                out.push_str(entry).map_err(|_| ExpandError::TooLong)?;
+
+                rest = &rest[end..];
+                expanded = true;
+                continue;
+            }
+            _ => (),
+        }
+
+        push(out, c)?;
+    }
+
+    Ok(expanded)
+}
+
+/// The entry `event` refers to, `event` being a reference without its `!`
+fn find<'h, const L: usize, const H: usize>(
+    event: &str,
+    history: &'h HistoryBuffer<String<L>, H>,
+) -> Option<&'h str> {
+    let len = history.len();
+    let index = if event == "!" {
+        len.checked_sub(1)
+    } else if let Some(back) = event.strip_prefix('-') {
+        back.parse::<usize>()
+            .ok()
+            .and_then(|back| len.checked_sub(back))
+    } else if let Ok(number) = event.parse::<usize>() {
+        number.checked_sub(1).filter(|&index| index < len)
+    } else {
+        return history
+            .oldest_ordered()
+            .filter(|entry| entry.starts_with(event))
+            .last()
+            .map(String::as_str);
+    };
+
+    index
+        .and_then(|index| history.oldest_ordered().nth(index))
+        .map(String::as_str)
+}
+
+/// Expands `old^new`, optionally followed by `^` and text to append
+fn substitute<'l, const N: usize, const L: usize, const H: usize>(
+    substitution: &'l str,
+    history: &HistoryBuffer<String<L>, H>,
+    out: &mut String<N>,
+) -> Result<(), ExpandError<'l>> {
+    let mut parts = substitution.splitn(3, '^');
+    let old = parts.next().unwrap_or("");
+    let new = parts.next().unwrap_or("");
+    let append = parts.next().unwrap_or("");
+
+    let previous = history.recent().ok_or(ExpandError::SubstitutionFailed)?;
+    let start = match previous.find(old) {
+        Some(start) if !old.is_empty() => start,
+        _ => return Err(ExpandError::SubstitutionFailed),
+    };
+
+    for part in [
+        &previous[..start],
+        new,
+        &previous[start + old.len()..],
+        append,
+    ] {
+        out.push_str(part).map_err(|_| ExpandError::TooLong)?;
+    }
+
+    Ok(())
+}
+
+fn push<'l, const N: usize>(out: &mut String<N>, c: char) -> Result<(), ExpandError<'l>> {
+    out.push(c).map_err(|_| ExpandError::TooLong)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> HistoryBuffer<String<32>, 4> {
+        let mut history = HistoryBuffer::new();
+        for line in ["led on", "i2c read 0x48 2", "dump regs"] {
+            history.write(String::from(line));
+        }
+        history
+    }
+
+    fn expanded(line: &str) -> Result<std::string::String, ExpandError<'_>> {
+        let mut out: String<32> = String::new();
+        expand(line, &history(), &mut out)?;
+        Ok(out.to_string())
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(expanded("!!").unwrap(), "dump regs");
+        assert_eq!(expanded("!1").unwrap(), "led on");
+        assert_eq!(expanded("!-2").unwrap(), "i2c read 0x48 2");
+        assert_eq!(expanded("!i2 4").unwrap(), "i2c read 0x48 2 4");
+        assert_eq!(expanded("echo !! done").unwrap(), "echo dump regs done");
+
+        assert_eq!(expanded("!4"), Err(ExpandError::EventNotFound("!4")));
+        assert_eq!(expanded("x !pwm"), Err(ExpandError::EventNotFound("!pwm")));
+        assert_eq!(expanded("!! !! !! !!"), Err(ExpandError::TooLong));
+    }
+
+    #[test]
+    fn test_literal() {
+        let mut out: String<32> = String::new();
+        for line in ["echo hi!", "a ! b", "x != y", "say '!!'", "say \\!!"] {
+            assert_eq!(expand(line, &history(), &mut out), Ok(false));
+            assert_eq!(out, line);
+        }
+    }
+
+    #[test]
+    fn test_substitute() {
+        let mut out: String<32> = String::new();
+        let mut history = history();
+        history.write(String::from("i2c read 0x48 2"));
+
+        assert_eq!(expand("^0x48^0x49", &history, &mut out), Ok(true));
+        assert_eq!(out, "i2c read 0x49 2");
+        assert_eq!(expand("^2^4^ -v", &history, &mut out), Ok(true));
+        assert_eq!(out, "i4c read 0x48 2 -v");
+        assert_eq!(
+            expand("^0x50^0x51", &history, &mut out),
+            Err(ExpandError::SubstitutionFailed)
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{self, Read, Write};
+
+use crate::telnet::{Received, Telnet};
+
+/// Reads what a telnet client types, such as over a
+/// [`TcpStream`](std::net::TcpStream), answering its option requests on the
+/// same stream
+pub struct TelnetReader<S> {
+    stream: S,
+    telnet: Telnet,
+}
+
+impl<S: Read + Write> TelnetReader<S> {
+    /// Offers the server's options to the client, see
+    /// [`Telnet::NEGOTIATION`]
+    pub fn new(mut stream: S) -> io::Result<TelnetReader<S>> {
+        stream.write_all(Telnet::NEGOTIATION)?;
+
+        Ok(TelnetReader {
+            stream,
+            telnet: Telnet::new(),
+        })
+    }
+
+    /// The width and height of the client's window, once it has told
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.telnet.window_size()
+    }
+}
+
+impl<S: Read + Write> Read for TelnetReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Reads again when everything read was taken out, as no input is
+        // the end of the stream
+        loop {
+            let read = self.stream.read(buf)?;
+            if read == 0 {
+                return Ok(0);
+            }
+
+            let mut len = 0;
+            for index in 0..read {
+                match self.telnet.receive(buf[index]) {
+                    Received::Input(byte) => {
+                        buf[len] = byte;
+                        len += 1;
+                    }
+                    Received::Reply(reply) => self.stream.write_all(&reply)?,
+                    Received::Nothing => (),
+                }
+            }
+
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// Writes to a telnet client, escaping 0xFF
+pub struct TelnetWriter<W> {
+    stream: W,
+}
+
+impl<W: Write> TelnetWriter<W> {
+    pub fn new(stream: W) -> TelnetWriter<W> {
+        TelnetWriter { stream }
+    }
+}
+
+impl<W: Write> Write for TelnetWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let escaped: Vec<u8> = Telnet::escape(buf).collect();
+        self.stream.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, CommandCallback, ReturnCode};
+    use heapless::String;
+
+    #[test]
+    fn test_telnet() {
+        use std::net::{TcpListener, TcpStream};
+
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |writer| {
+            write!(writer.unwrap(), "on").unwrap();
+            Ok(ReturnCode::Success)
+        };
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut negotiation = [0; 9];
+            stream.read_exact(&mut negotiation).unwrap();
+            assert_eq!(negotiation, Telnet::NEGOTIATION);
+
+            // Agrees to the offers, sends its window size and asks for an
+            // option the server refuses, then types a line and Ctrl+D
+            stream
+                .write_all(
+                    b"\xff\xfd\x01\xff\xfb\x1f\xff\xfa\x1f\x00\x50\x00\x18\xff\xf0\xff\xfd\x18",
+                )
+                .unwrap();
+            stream.write_all(b"led\r\0\x04").unwrap();
+
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = TelnetReader::new(&stream).unwrap();
+        cli.run_std(&mut reader, TelnetWriter::new(&stream))
+            .unwrap();
+        assert_eq!(reader.window_size(), Some((80, 24)));
+        drop(stream);
+
+        let received = client.join().unwrap();
+        assert_eq!(received, b"\r\ncli> \xff\xfc\x18led\r\ncli> on\r\ncli> ");
+    }
+}
@@ -0,0 +1,136 @@
+use core::fmt;
+
+use embedded_io::{Read, ReadReady, Write};
+
+/// [`core::fmt::Write`] over an [`embedded_io::Write`]
+pub(crate) struct IoWriter<'w, W> {
+    port: &'w mut W,
+}
+
+impl<'w, W: Write> IoWriter<'w, W> {
+    pub(crate) fn new(port: &'w mut W) -> IoWriter<'w, W> {
+        IoWriter { port }
+    }
+
+    /// The port, to read from when it does both the reading and the writing
+    pub(crate) fn port(&mut self) -> &mut W {
+        self.port
+    }
+}
+
+impl<W: Write> fmt::Write for IoWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.port.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Reads a byte if one is waiting, so that the CLI never blocks on `rx`.
+/// The end of the input is taken as no input.
+pub(crate) fn read_byte<R: Read + ReadReady>(rx: &mut R) -> nb::Result<u8, R::Error> {
+    if !rx.read_ready()? {
+        return Err(nb::Error::WouldBlock);
+    }
+
+    let mut byte = [0];
+    match rx.read(&mut byte)? {
+        0 => Err(nb::Error::WouldBlock),
+        _ => Ok(byte[0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, CliError, CommandCallback, ReturnCode, RunStatus};
+    use embedded_io::{ErrorKind, ErrorType};
+    use heapless::String;
+
+    /// Hands out `input`, then fails if `fail` is set or reports the end of
+    /// the input, and collects what is written in `output`
+    #[derive(Default)]
+    struct Port {
+        input: std::collections::VecDeque<u8>,
+        output: std::vec::Vec<u8>,
+        fail: bool,
+    }
+
+    impl ErrorType for Port {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            match self.input.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None if self.fail => Err(ErrorKind::Other),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl ReadReady for Port {
+        fn read_ready(&mut self) -> Result<bool, ErrorKind> {
+            Ok(!self.input.is_empty() || self.fail)
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            // Short writes, to go through `write_all`
+            self.output.push(buf[0]);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> Result<(), ErrorKind> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_byte() {
+        let mut port = Port::default();
+        assert_eq!(read_byte(&mut port), Err(nb::Error::WouldBlock));
+
+        port.input.push_back(b'l');
+        assert_eq!(read_byte(&mut port), Ok(b'l'));
+
+        port.fail = true;
+        assert_eq!(
+            read_byte(&mut port),
+            Err(nb::Error::Other(ErrorKind::Other))
+        );
+    }
+
+    #[test]
+    fn test_run_io() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        let mut port = Port::default();
+        cli.init_io(&mut port).unwrap();
+        assert!(matches!(cli.run_io(&mut port), Ok(RunStatus::Idle)));
+
+        port.input.extend(b"led\r");
+        assert!(matches!(
+            cli.run_io(&mut port),
+            Ok(RunStatus::CommandExecuted(ReturnCode::Success))
+        ));
+        assert_eq!(port.output, b"\r\ncli> led\r\ncli> \r\ncli> ");
+
+        let mut tx = Port::default();
+        port.input.extend(b"le");
+        port.fail = true;
+        assert!(matches!(
+            cli.run_io_split(&mut port, &mut tx),
+            Err(CliError::ReadError(ErrorKind::Other))
+        ));
+        assert_eq!(tx.output, b"le");
+    }
+}
@@ -0,0 +1,288 @@
+use heapless::Vec;
+
+use crate::keys::Key;
+use crate::CliError;
+
+/// Maximum number of keys that can be bound or unbound on top of a
+/// [`Keymap`]'s table
+pub const MAX_KEY_OVERRIDES: usize = 8;
+
+/// What the `Cli` does for a key. The vi actions do nothing without the
+/// `vi` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Inserts the character at the cursor. Printable characters that are
+    /// neither bound nor unbound get this action.
+    Insert(char),
+    /// Runs the line
+    AcceptLine,
+    /// Starts a new prompt on the next line, keeping the line being edited
+    LineFeed,
+    /// Drops the line being edited and starts a new prompt
+    CancelLine,
+    /// Clears the screen and redraws the prompt and the line
+    ClearScreen,
+    /// Completes the command name before the cursor, listing the
+    /// candidates when done twice in a row
+    Complete,
+    Backspace,
+    Delete,
+    /// Deletes the character under the cursor, or ends the input with
+    /// [`CliError::EndOfInput`] on an empty line
+    DeleteOrEndOfInput,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Moves to the start of the word before the cursor
+    WordLeft,
+    /// Moves to the end of the word after the cursor
+    WordRight,
+    /// Kills from the cursor to the end of the line
+    KillToEnd,
+    /// Kills from the start of the line to the cursor
+    KillToStart,
+    /// Kills the word before the cursor
+    KillWord,
+    /// Inserts the most recently killed text
+    Yank,
+    /// Straight after a yank, swaps the yanked text for older killed text
+    YankPop,
+    HistoryPrev,
+    HistoryNext,
+    /// Searches back through the history as the query is typed, stepping
+    /// to older matches when done again
+    ReverseSearch,
+    /// Gives up a history search, putting back the line as it was
+    Abort,
+    /// Switches to vi normal mode
+    ViNormalMode,
+    /// Switches to vi insert mode, before the cursor (`i`)
+    ViInsert,
+    /// Switches to vi insert mode at the start of the line (`I`)
+    ViInsertStart,
+    /// Switches to vi insert mode, after the cursor (`a`)
+    ViAppend,
+    /// Switches to vi insert mode at the end of the line (`A`)
+    ViAppendEnd,
+    /// Moves to the start of the next word (`w`)
+    ViNextWord,
+    /// Deletes up to where the following motion goes (`d`)
+    ViDelete,
+    /// Deletes up to where the following motion goes, then switches to
+    /// insert mode (`c`)
+    ViChange,
+}
+
+/// The default bindings, following readline's emacs mode
+pub const EMACS_BINDINGS: &[(Key, Action)] = &[
+    (Key::Enter, Action::AcceptLine),
+    (Key::Ctrl('j'), Action::LineFeed),
+    (Key::Ctrl('c'), Action::CancelLine),
+    (Key::Ctrl('l'), Action::ClearScreen),
+    (Key::Tab, Action::Complete),
+    (Key::Backspace, Action::Backspace),
+    (Key::Delete, Action::Delete),
+    (Key::Ctrl('d'), Action::DeleteOrEndOfInput),
+    (Key::Left, Action::Left),
+    (Key::Ctrl('b'), Action::Left),
+    (Key::Right, Action::Right),
+    (Key::Ctrl('f'), Action::Right),
+    (Key::Home, Action::Home),
+    (Key::Ctrl('a'), Action::Home),
+    (Key::End, Action::End),
+    (Key::Ctrl('e'), Action::End),
+    (Key::Alt('b'), Action::WordLeft),
+    (Key::Alt('f'), Action::WordRight),
+    (Key::CtrlLeft, Action::WordLeft),
+    (Key::CtrlRight, Action::WordRight),
+    (Key::Ctrl('k'), Action::KillToEnd),
+    (Key::Ctrl('u'), Action::KillToStart),
+    (Key::Ctrl('w'), Action::KillWord),
+    (Key::Ctrl('y'), Action::Yank),
+    (Key::Alt('y'), Action::YankPop),
+    (Key::Up, Action::HistoryPrev),
+    (Key::Ctrl('p'), Action::HistoryPrev),
+    (Key::Down, Action::HistoryNext),
+    (Key::Ctrl('n'), Action::HistoryNext),
+    (Key::Ctrl('r'), Action::ReverseSearch),
+    (Key::Ctrl('g'), Action::Abort),
+];
+
+/// Bindings for vi insert mode. Esc followed by a key switches to normal
+/// mode before handling the key; a lone Esc only takes effect with the next
+/// key, as the terminal sends it as the start of an escape sequence.
+#[cfg(feature = "vi")]
+pub const VI_INSERT_BINDINGS: &[(Key, Action)] = &[
+    (Key::Escape, Action::ViNormalMode),
+    (Key::Enter, Action::AcceptLine),
+    (Key::Ctrl('j'), Action::LineFeed),
+    (Key::Ctrl('c'), Action::CancelLine),
+    (Key::Ctrl('l'), Action::ClearScreen),
+    (Key::Tab, Action::Complete),
+    (Key::Backspace, Action::Backspace),
+    (Key::Delete, Action::Delete),
+    (Key::Ctrl('d'), Action::DeleteOrEndOfInput),
+    (Key::Left, Action::Left),
+    (Key::Right, Action::Right),
+    (Key::Home, Action::Home),
+    (Key::End, Action::End),
+    (Key::Ctrl('w'), Action::KillWord),
+    (Key::Ctrl('u'), Action::KillToStart),
+    (Key::Up, Action::HistoryPrev),
+    (Key::Down, Action::HistoryNext),
+    (Key::Ctrl('r'), Action::ReverseSearch),
+    (Key::Ctrl('g'), Action::Abort),
+];
+
+/// Bindings for vi normal mode. Characters are not inserted in normal mode,
+/// so [`Action::Insert`] does nothing there.
+#[cfg(feature = "vi")]
+pub const VI_NORMAL_BINDINGS: &[(Key, Action)] = &[
+    (Key::Enter, Action::AcceptLine),
+    (Key::Ctrl('c'), Action::CancelLine),
+    (Key::Ctrl('l'), Action::ClearScreen),
+    (Key::Ctrl('d'), Action::DeleteOrEndOfInput),
+    (Key::Char('i'), Action::ViInsert),
+    (Key::Char('I'), Action::ViInsertStart),
+    (Key::Char('a'), Action::ViAppend),
+    (Key::Char('A'), Action::ViAppendEnd),
+    (Key::Char('h'), Action::Left),
+    (Key::Left, Action::Left),
+    (Key::Backspace, Action::Left),
+    (Key::Char('l'), Action::Right),
+    (Key::Right, Action::Right),
+    (Key::Char(' '), Action::Right),
+    (Key::Char('0'), Action::Home),
+    (Key::Home, Action::Home),
+    (Key::Char('$'), Action::End),
+    (Key::End, Action::End),
+    (Key::Char('w'), Action::ViNextWord),
+    (Key::Char('b'), Action::WordLeft),
+    (Key::Char('e'), Action::WordRight),
+    (Key::Char('x'), Action::Delete),
+    (Key::Delete, Action::Delete),
+    (Key::Char('d'), Action::ViDelete),
+    (Key::Char('c'), Action::ViChange),
+    (Key::Char('D'), Action::KillToEnd),
+    (Key::Char('p'), Action::Yank),
+    (Key::Char('k'), Action::HistoryPrev),
+    (Key::Up, Action::HistoryPrev),
+    (Key::Char('j'), Action::HistoryNext),
+    (Key::Down, Action::HistoryNext),
+];
+
+/// Maps keys to the [`Action`]s the `Cli` takes for them.
+///
+/// The bindings come from a table, normally one of the provided ones, with
+/// a few keys rebound or unbound on top of it:
+///
+/// ```ignore
+/// cli.keymap_mut().bind(Key::Ctrl('c'), Action::ClearScreen)?;
+/// cli.keymap_mut().unbind(Key::Up)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Keymap<'k> {
+    bindings: &'k [(Key, Action)],
+    /// Checked before `bindings`, `None` unbinds the key
+    overrides: Vec<(Key, Option<Action>), MAX_KEY_OVERRIDES>,
+}
+
+impl<'k> Keymap<'k> {
+    pub fn new(bindings: &'k [(Key, Action)]) -> Keymap<'k> {
+        Keymap {
+            bindings,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Binds `key` to `action`, replacing any existing binding
+    pub fn bind(&mut self, key: Key, action: Action) -> Result<(), CliError> {
+        self.set(key, Some(action))
+    }
+
+    /// Removes the binding for `key`, so that the key is ignored
+    pub fn unbind(&mut self, key: Key) -> Result<(), CliError> {
+        self.set(key, None)
+    }
+
+    /// The action for `key`, `None` if the key should be ignored
+    pub fn lookup(&self, key: Key) -> Option<Action> {
+        if let Some(&(_, action)) = self.overrides.iter().find(|&&(bound, _)| bound == key) {
+            return action;
+        }
+
+        match self.bindings.iter().find(|&&(bound, _)| bound == key) {
+            Some(&(_, action)) => Some(action),
+            None => match key {
+                Key::Char(c) => Some(Action::Insert(c)),
+                _ => None,
+            },
+        }
+    }
+
+    fn set(&mut self, key: Key, action: Option<Action>) -> Result<(), CliError> {
+        match self.overrides.iter_mut().find(|(bound, _)| *bound == key) {
+            Some(binding) => binding.1 = action,
+            None => self
+                .overrides
+                .push((key, action))
+                .map_err(|_| CliError::KeymapFull)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Keymap<'_> {
+    /// The emacs bindings, or the vi insert mode bindings with the `vi`
+    /// feature
+    fn default() -> Self {
+        #[cfg(feature = "vi")]
+        let bindings = VI_INSERT_BINDINGS;
+        #[cfg(not(feature = "vi"))]
+        let bindings = EMACS_BINDINGS;
+
+        Keymap::new(bindings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let keymap = Keymap::new(EMACS_BINDINGS);
+
+        assert_eq!(keymap.lookup(Key::Ctrl('a')), Some(Action::Home));
+        assert_eq!(keymap.lookup(Key::Enter), Some(Action::AcceptLine));
+        assert_eq!(keymap.lookup(Key::Char('a')), Some(Action::Insert('a')));
+        assert_eq!(keymap.lookup(Key::Function(1)), None);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut keymap = Keymap::new(EMACS_BINDINGS);
+
+        keymap.bind(Key::Ctrl('c'), Action::ClearScreen).unwrap();
+        keymap.unbind(Key::Up).unwrap();
+        keymap.bind(Key::Function(1), Action::Insert('?')).unwrap();
+        keymap.bind(Key::Ctrl('c'), Action::KillToStart).unwrap();
+        keymap.unbind(Key::Char('#')).unwrap();
+
+        assert_eq!(keymap.lookup(Key::Ctrl('c')), Some(Action::KillToStart));
+        assert_eq!(keymap.lookup(Key::Up), None);
+        assert_eq!(keymap.lookup(Key::Function(1)), Some(Action::Insert('?')));
+        assert_eq!(keymap.lookup(Key::Down), Some(Action::HistoryNext));
+        assert_eq!(keymap.lookup(Key::Char('#')), None);
+
+        for n in 2..=5 {
+            keymap.unbind(Key::Function(n)).unwrap();
+        }
+        assert!(matches!(
+            keymap.unbind(Key::Function(6)),
+            Err(CliError::KeymapFull)
+        ));
+    }
+}
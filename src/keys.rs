@@ -0,0 +1,323 @@
+/// A key press decoded from the bytes sent by a terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// Printable ASCII character
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    /// Control character, e.g. `Ctrl('a')` for 0x01
+    Ctrl(char),
+    /// Character typed with Alt (or Meta) held, sent as ESC followed by it
+    Alt(char),
+    /// Esc on its own, seen once the key after it is not part of an escape
+    /// sequence
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    CtrlUp,
+    CtrlDown,
+    CtrlLeft,
+    CtrlRight,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// Function key F1 to F12
+    Function(u8),
+}
+
+/// Maximum number of numeric parameters kept from a CSI sequence
+const MAX_PARAMS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// Seen ESC
+    Escape,
+    /// Seen ESC [
+    Csi,
+    /// Seen ESC O
+    Ss3,
+}
+
+/// Turns the bytes read from a terminal into [`Key`]s.
+///
+/// Handles CSI (`ESC [ params final`) and SS3 (`ESC O final`) sequences as
+/// sent by VT100/xterm compatible terminals in normal and application cursor
+/// mode. The bytes of escape sequences are never passed on as characters;
+/// sequences that are not understood are dropped whole.
+///
+/// ESC followed by a byte that cannot follow it in a sequence, such as
+/// Enter, is decoded as [`Key::Escape`] with the byte kept back to be
+/// decoded by [`KeyDecoder::next_key`].
+#[derive(Debug, Clone)]
+pub struct KeyDecoder {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    /// The byte after a lone ESC, not decoded yet
+    pending: Option<u8>,
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyDecoder {
+    pub const fn new() -> KeyDecoder {
+        KeyDecoder {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            pending: None,
+        }
+    }
+
+    /// Feed one byte, returning a key once a full key press has been seen
+    pub fn decode(&mut self, byte: u8) -> Option<Key> {
+        match self.state {
+            State::Ground => self.ground(byte),
+            State::Escape => self.escape(byte),
+            State::Csi => self.csi(byte),
+            State::Ss3 => {
+                self.state = State::Ground;
+                Self::ss3(byte)
+            }
+        }
+    }
+
+    /// The key sent by the byte kept back after [`Key::Escape`], to be
+    /// asked for after each key [`KeyDecoder::decode`] returns
+    pub fn next_key(&mut self) -> Option<Key> {
+        let byte = self.pending.take()?;
+        self.ground(byte)
+    }
+
+    fn ground(&mut self, byte: u8) -> Option<Key> {
+        match byte {
+            0x1B => {
+                self.state = State::Escape;
+                None
+            }
+            b'\r' => Some(Key::Enter),
+            b'\t' => Some(Key::Tab),
+            0x08 | 0x7F => Some(Key::Backspace),
+            0x01..=0x1A => Some(Key::Ctrl((b'a' + byte - 1) as char)),
+            0x20..=0x7E => Some(Key::Char(byte as char)),
+            _ => None,
+        }
+    }
+
+    fn escape(&mut self, byte: u8) -> Option<Key> {
+        self.state = State::Ground;
+
+        match byte {
+            b'[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.param_count = 0;
+                None
+            }
+            b'O' => {
+                self.state = State::Ss3;
+                None
+            }
+            // A second ESC starts a new sequence
+            0x1B => {
+                self.state = State::Escape;
+                None
+            }
+            0x20..=0x7E => Some(Key::Alt(byte as char)),
+            _ => {
+                self.pending = Some(byte);
+                Some(Key::Escape)
+            }
+        }
+    }
+
+    fn csi(&mut self, byte: u8) -> Option<Key> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.param_count == 0 {
+                    self.param_count = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            b';' => {
+                self.param_count = (self.param_count.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            // Remaining parameter and intermediate bytes
+            0x20..=0x3F => None,
+            // Final byte
+            0x40..=0x7E => {
+                self.state = State::Ground;
+                self.csi_final(byte)
+            }
+            // CAN and SUB cancel the sequence
+            0x18 | 0x1A => {
+                self.state = State::Ground;
+                None
+            }
+            0x1B => {
+                self.state = State::Escape;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn csi_final(&self, byte: u8) -> Option<Key> {
+        let ctrl = self.params[1] == 5;
+
+        match (byte, ctrl) {
+            (b'A', false) => Some(Key::Up),
+            (b'B', false) => Some(Key::Down),
+            (b'C', false) => Some(Key::Right),
+            (b'D', false) => Some(Key::Left),
+            (b'A', true) => Some(Key::CtrlUp),
+            (b'B', true) => Some(Key::CtrlDown),
+            (b'C', true) => Some(Key::CtrlRight),
+            (b'D', true) => Some(Key::CtrlLeft),
+            (b'H', _) => Some(Key::Home),
+            (b'F', _) => Some(Key::End),
+            (b'~', _) => match self.params[0] {
+                1 | 7 => Some(Key::Home),
+                2 => Some(Key::Insert),
+                3 => Some(Key::Delete),
+                4 | 8 => Some(Key::End),
+                5 => Some(Key::PageUp),
+                6 => Some(Key::PageDown),
+                11..=15 => Some(Key::Function((self.params[0] - 10) as u8)),
+                17..=21 => Some(Key::Function((self.params[0] - 11) as u8)),
+                23 | 24 => Some(Key::Function((self.params[0] - 12) as u8)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn ss3(byte: u8) -> Option<Key> {
+        match byte {
+            b'A' => Some(Key::Up),
+            b'B' => Some(Key::Down),
+            b'C' => Some(Key::Right),
+            b'D' => Some(Key::Left),
+            b'H' => Some(Key::Home),
+            b'F' => Some(Key::End),
+            b'P'..=b'S' => Some(Key::Function(byte - b'P' + 1)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> std::vec::Vec<Key> {
+        let mut decoder = KeyDecoder::new();
+        let mut keys = std::vec::Vec::new();
+        for &byte in bytes {
+            keys.extend(decoder.decode(byte));
+            keys.extend(decoder.next_key());
+        }
+        keys
+    }
+
+    #[test]
+    fn test_plain_bytes() {
+        assert_eq!(
+            decode(b"AB\r\t\x08\x7f\x01\n"),
+            [
+                Key::Char('A'),
+                Key::Char('B'),
+                Key::Enter,
+                Key::Tab,
+                Key::Backspace,
+                Key::Backspace,
+                Key::Ctrl('a'),
+                Key::Ctrl('j'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csi() {
+        assert_eq!(
+            decode(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1b[H\x1b[F"),
+            [
+                Key::Up,
+                Key::Down,
+                Key::Right,
+                Key::Left,
+                Key::Home,
+                Key::End
+            ]
+        );
+        assert_eq!(
+            decode(b"\x1b[3~\x1b[1~\x1b[4~\x1b[5~\x1b[6~\x1b[2~\x1b[15~\x1b[24~"),
+            [
+                Key::Delete,
+                Key::Home,
+                Key::End,
+                Key::PageUp,
+                Key::PageDown,
+                Key::Insert,
+                Key::Function(5),
+                Key::Function(12)
+            ]
+        );
+        assert_eq!(
+            decode(b"\x1b[1;5D\x1b[1;5C\x1b[1;2A"),
+            [Key::CtrlLeft, Key::CtrlRight, Key::Up]
+        );
+    }
+
+    #[test]
+    fn test_ss3() {
+        assert_eq!(
+            decode(b"\x1bOA\x1bOD\x1bOH\x1bOP"),
+            [Key::Up, Key::Left, Key::Home, Key::Function(1)]
+        );
+    }
+
+    #[test]
+    fn test_alt_and_unknown() {
+        assert_eq!(decode(b"\x1bb\x1bf"), [Key::Alt('b'), Key::Alt('f')]);
+        // Unknown sequences are swallowed, including their parameters
+        assert_eq!(decode(b"\x1b[99;99;99zx"), [Key::Char('x')]);
+        assert_eq!(decode(b"\x1b[12\x18A"), [Key::Char('A')]);
+        assert_eq!(decode(b"\x1b\x1b[A"), [Key::Up]);
+    }
+
+    #[test]
+    fn test_escape() {
+        // The key after a lone Esc is still decoded
+        assert_eq!(
+            decode(b"\x1b\r\x1b\x7f\x1b\x12\x1b\x1bb"),
+            [
+                Key::Escape,
+                Key::Enter,
+                Key::Escape,
+                Key::Backspace,
+                Key::Escape,
+                Key::Ctrl('r'),
+                Key::Alt('b'),
+            ]
+        );
+        assert_eq!(decode(b"\x1b\x1b\r"), [Key::Escape, Key::Enter]);
+    }
+}
@@ -1,48 +1,391 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+use core::convert::Infallible;
+#[cfg(feature = "embedded-hal-02")]
 use embedded_hal::serial::{Read, Write};
+
 use heapless::{HistoryBuffer, String};
 
-pub use command_processor::{
-    CommandCallback, CommandCallbackReturn, CommandProcessor, CommandProcessorError, ReturnCode,
+mod args;
+mod command;
+mod complete;
+mod expand;
+#[cfg(all(feature = "std", feature = "telnet"))]
+mod hosted;
+#[cfg(feature = "embedded-io")]
+mod io;
+mod keymap;
+mod keys;
+mod line;
+#[cfg(feature = "pty")]
+mod pty;
+mod schema;
+mod search;
+mod sink;
+#[cfg(feature = "history-storage")]
+mod storage;
+#[cfg(feature = "telnet")]
+mod telnet;
+#[cfg(feature = "vi")]
+mod vi;
+
+pub use args::{Args, TokenizeError, MAX_TOKENS};
+#[cfg(feature = "async")]
+pub use command::AsyncCommands;
+pub use command::{
+    ArgsCommandCallback, CommandCallback, CommandCallbackReturn, CommandClosure,
+    CommandProcessorError, ContextCommandCallback, ReturnCode,
 };
-
-pub enum CliError {
+pub use complete::{CompletionHook, Completions, MAX_COMPLETIONS};
+#[cfg(all(feature = "std", feature = "telnet"))]
+pub use hosted::{TelnetReader, TelnetWriter};
+pub use keymap::{Action, Keymap, EMACS_BINDINGS, MAX_KEY_OVERRIDES};
+#[cfg(feature = "vi")]
+pub use keymap::{VI_INSERT_BINDINGS, VI_NORMAL_BINDINGS};
+pub use keys::{Key, KeyDecoder};
+#[cfg(feature = "pty")]
+pub use pty::{Pty, RawMode};
+pub use schema::{ArgError, ArgErrorReason, ArgKind, ArgSpec, FromArg, OptionName};
+pub use sink::ByteSink;
+#[cfg(feature = "history-storage")]
+pub use storage::{HistoryStore, RamFlash};
+#[cfg(feature = "telnet")]
+pub use telnet::{Received, Telnet};
+
+use args::Tokens;
+use command::{Callback, CommandTable};
+use expand::{expand, ExpandError};
+#[cfg(feature = "embedded-io")]
+use io::IoWriter;
+use line::{cursor_left, LineBuffer, Motion};
+use schema::complete_arg;
+use search::Search;
+#[cfg(feature = "async")]
+use sink::AsyncWriter;
+#[cfg(feature = "embedded-hal-02")]
+use sink::SerialWriter;
+#[cfg(feature = "vi")]
+use vi::{Mode, ViState};
+
+/// Arguments of the built-in `history` command
+#[cfg(feature = "history-command")]
+const HISTORY_ARGS: &[ArgSpec<'static>] = &[
+    ArgSpec::flag(Some('c'), "clear"),
+    ArgSpec::optional("count", ArgKind::U32),
+];
+
+/// Errors from the `Cli`. `E` is the error type of the serial port for the
+/// errors from [`Cli::run`], which can fail to read.
+#[derive(Debug)]
+pub enum CliError<E = Infallible> {
     CommandProcessorError(CommandProcessorError),
-    ReadError,
+    /// Reading from the serial port failed, for example with a framing or
+    /// overrun error. No input being waiting is not an error.
+    ReadError(E),
     WriteError,
     ReadBufferError,
     CommandBufferError,
+    TokenizeError(TokenizeError),
+    InvalidArgument(ArgError),
+    UnknownCommand,
+    DuplicateCommand,
+    CommandTableFull,
+    /// Ctrl+D was pressed on an empty line
+    EndOfInput,
+    KeymapFull,
+    /// A key would have made the line longer than `LINE_SIZE`. The bell was
+    /// rung and the line left as it was.
+    LineTooLong,
+    /// A history reference such as `!pwm` or `^old^new` matched nothing
+    HistoryExpansion,
+    /// The prompt is longer than `PROMPT_SIZE`
+    PromptTooLong,
+    /// Loading or saving the history failed, or the flash region does not
+    /// suit it
+    #[cfg(feature = "history-storage")]
+    StorageError(embedded_storage::nor_flash::NorFlashErrorKind),
+    /// A command added with [`Cli::add_async_command`] was entered while not
+    /// running with [`Cli::run_async_with`]
+    #[cfg(feature = "async")]
+    AsyncCommand,
+}
+
+#[cfg(any(
+    feature = "embedded-hal-02",
+    feature = "embedded-io",
+    feature = "async"
+))]
+impl CliError {
+    /// The same error, as one of the errors [`Cli::run`] can return
+    fn widen<E>(self) -> CliError<E> {
+        match self {
+            CliError::CommandProcessorError(error) => CliError::CommandProcessorError(error),
+            CliError::ReadError(never) => match never {},
+            CliError::WriteError => CliError::WriteError,
+            CliError::ReadBufferError => CliError::ReadBufferError,
+            CliError::CommandBufferError => CliError::CommandBufferError,
+            CliError::TokenizeError(error) => CliError::TokenizeError(error),
+            CliError::InvalidArgument(error) => CliError::InvalidArgument(error),
+            CliError::UnknownCommand => CliError::UnknownCommand,
+            CliError::DuplicateCommand => CliError::DuplicateCommand,
+            CliError::CommandTableFull => CliError::CommandTableFull,
+            CliError::EndOfInput => CliError::EndOfInput,
+            CliError::KeymapFull => CliError::KeymapFull,
+            CliError::LineTooLong => CliError::LineTooLong,
+            CliError::HistoryExpansion => CliError::HistoryExpansion,
+            CliError::PromptTooLong => CliError::PromptTooLong,
+            #[cfg(feature = "history-storage")]
+            CliError::StorageError(kind) => CliError::StorageError(kind),
+            #[cfg(feature = "async")]
+            CliError::AsyncCommand => CliError::AsyncCommand,
+        }
+    }
+}
+
+/// What a call to [`Cli::run`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// There was no input waiting
+    Idle,
+    /// All the input waiting was handled without finishing the line
+    LineEdited,
+    /// Enter ran the line, with the command's return code
+    CommandExecuted(ReturnCode),
 }
 
-pub struct Cli<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize> {
-    command_processor: CommandProcessor<'a, NUM_COMMANDS, HELP_STR_SIZE>,
-    prompt: String<32>,
-    read_buffer: String<32>,
-    command_buffer: String<32>,
-    history_buffer: HistoryBuffer<String<32>, 8>,
+/// The command line interface.
+///
+/// `Ctx` is the type handed as `&mut Ctx` to context callbacks and closures
+/// by [`Cli::run_with_context`], typically a struct holding the
+/// peripherals the commands drive.
+///
+/// `LINE_SIZE` is the longest line that can be typed, `HISTORY_SIZE` the
+/// number of lines kept in the history and `PROMPT_SIZE` the longest prompt.
+/// The history takes `LINE_SIZE * HISTORY_SIZE` bytes of RAM.
+pub struct Cli<
+    'a,
+    const NUM_COMMANDS: usize,
+    const HELP_STR_SIZE: usize,
+    Ctx = (),
+    const LINE_SIZE: usize = 32,
+    const HISTORY_SIZE: usize = 8,
+    const PROMPT_SIZE: usize = 32,
+> {
+    commands: CommandTable<'a, NUM_COMMANDS, HELP_STR_SIZE, Ctx>,
+    prompt: String<PROMPT_SIZE>,
+    line: LineBuffer<LINE_SIZE>,
+    history_buffer: HistoryBuffer<String<LINE_SIZE>, HISTORY_SIZE>,
+    /// How far back Up has gone through the history, 1 being the most
+    /// recent entry and 0 the line being typed
     history_buffer_idx: usize,
+    history_ignore_empty: bool,
+    history_ignore_duplicates: bool,
+    history_ignore_space: bool,
+    history_expansion: bool,
+    key_decoder: KeyDecoder,
+    keymap: Keymap<'a>,
+    /// The last key completed a word with several candidates
+    completion_pending: bool,
+    autosuggestions: bool,
+    /// Length of the part of a history entry shown dimmed after the line
+    suggestion_shown: usize,
+    /// The reverse history search under way, if any
+    search: Option<Search<LINE_SIZE>>,
+    /// A line was added to the history since it was last loaded or saved
+    #[cfg(feature = "history-storage")]
+    history_changed: bool,
+    /// Set while `run_async_with_context` feeds a byte, for async commands
+    /// to be left to it rather than failing
+    #[cfg(feature = "async")]
+    running_async: bool,
+    /// The line names an async command, which is left to run
+    #[cfg(feature = "async")]
+    async_pending: bool,
+    #[cfg(feature = "vi")]
+    vi_normal_keymap: Keymap<'a>,
+    #[cfg(feature = "vi")]
+    vi: ViState,
 }
 
-impl<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize> Default
-    for Cli<'a, NUM_COMMANDS, HELP_STR_SIZE>
+impl<
+        'a,
+        const NUM_COMMANDS: usize,
+        const HELP_STR_SIZE: usize,
+        Ctx,
+        const LINE_SIZE: usize,
+        const HISTORY_SIZE: usize,
+        const PROMPT_SIZE: usize,
+    > Default for Cli<'a, NUM_COMMANDS, HELP_STR_SIZE, Ctx, LINE_SIZE, HISTORY_SIZE, PROMPT_SIZE>
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize>
-    Cli<'a, NUM_COMMANDS, HELP_STR_SIZE>
+impl<
+        'a,
+        const NUM_COMMANDS: usize,
+        const HELP_STR_SIZE: usize,
+        const LINE_SIZE: usize,
+        const HISTORY_SIZE: usize,
+        const PROMPT_SIZE: usize,
+    > Cli<'a, NUM_COMMANDS, HELP_STR_SIZE, (), LINE_SIZE, HISTORY_SIZE, PROMPT_SIZE>
+{
+    #[cfg(feature = "embedded-hal-02")]
+    pub fn run<T: Read<u8> + Write<u8> + core::fmt::Write>(
+        &mut self,
+        serial: &mut T,
+    ) -> Result<RunStatus, CliError<<T as Read<u8>>::Error>> {
+        self.run_with_context(serial, &mut ())
+    }
+
+    /// Run the CLI over the two halves of a split serial port, see
+    /// [`Cli::run_split_with_context`]
+    #[cfg(feature = "embedded-hal-02")]
+    pub fn run_split<R: Read<u8>, W: Write<u8>>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<RunStatus, CliError<R::Error>> {
+        self.run_split_with_context(rx, tx, &mut ())
+    }
+
+    /// Run the CLI over an `embedded-io` port, see
+    /// [`Cli::run_io_with_context`]
+    #[cfg(feature = "embedded-io")]
+    pub fn run_io<T: embedded_io::Read + embedded_io::ReadReady + embedded_io::Write>(
+        &mut self,
+        port: &mut T,
+    ) -> Result<RunStatus, CliError<<T as embedded_io::ErrorType>::Error>> {
+        self.run_io_with_context(port, &mut ())
+    }
+
+    /// Run the CLI over an `embedded-io` reader and writer, see
+    /// [`Cli::run_io_split_with_context`]
+    #[cfg(feature = "embedded-io")]
+    pub fn run_io_split<R: embedded_io::Read + embedded_io::ReadReady, W: embedded_io::Write>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<RunStatus, CliError<R::Error>> {
+        self.run_io_split_with_context(rx, tx, &mut ())
+    }
+
+    /// Run the CLI asynchronously, without async commands, see
+    /// [`Cli::run_async_with_context`]
+    #[cfg(feature = "async")]
+    pub async fn run_async<
+        const OUTPUT_SIZE: usize,
+        R: embedded_io_async::Read,
+        W: embedded_io_async::Write,
+    >(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<ReturnCode, CliError<R::Error>> {
+        self.run_async_with_context::<OUTPUT_SIZE, _, _, _>(rx, tx, &mut (), &mut ())
+            .await
+    }
+
+    /// Run the CLI asynchronously, with `commands` running the async
+    /// commands, see [`Cli::run_async_with_context`]
+    #[cfg(feature = "async")]
+    pub async fn run_async_with<
+        const OUTPUT_SIZE: usize,
+        R: embedded_io_async::Read,
+        W: embedded_io_async::Write,
+        C: AsyncCommands,
+    >(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+        commands: &mut C,
+    ) -> Result<ReturnCode, CliError<R::Error>> {
+        self.run_async_with_context::<OUTPUT_SIZE, _, _, _>(rx, tx, commands, &mut ())
+            .await
+    }
+
+    /// Run the CLI over a `std` reader and writer, see
+    /// [`Cli::run_std_with_context`]
+    #[cfg(feature = "std")]
+    pub fn run_std<R: std::io::Read, W: std::io::Write>(
+        &mut self,
+        input: R,
+        output: W,
+    ) -> Result<(), CliError<std::io::Error>> {
+        self.run_std_with_context(input, output, &mut ())
+    }
+
+    /// Run the CLI on the terminal the program was started from, in
+    /// [`RawMode`] unless stdin is not a terminal, see
+    /// [`Cli::run_std_with_context`]
+    #[cfg(feature = "pty")]
+    pub fn run_stdio(&mut self) -> Result<(), CliError<std::io::Error>> {
+        let _raw = RawMode::enable(libc::STDIN_FILENO);
+        self.run_std(std::io::stdin().lock(), std::io::stdout().lock())
+    }
+
+    /// Handles a byte of input, see [`Cli::feed_with_context`]
+    pub fn feed<W: core::fmt::Write>(
+        &mut self,
+        byte: u8,
+        out: &mut W,
+    ) -> Result<RunStatus, CliError> {
+        self.feed_with_context(byte, out, &mut ())
+    }
+
+    /// Handles `bytes` up to the first line run, see
+    /// [`Cli::feed_slice_with_context`]
+    pub fn feed_slice<W: core::fmt::Write>(
+        &mut self,
+        bytes: &[u8],
+        out: &mut W,
+    ) -> (usize, Result<RunStatus, CliError>) {
+        self.feed_slice_with_context(bytes, out, &mut ())
+    }
+}
+
+impl<
+        'a,
+        const NUM_COMMANDS: usize,
+        const HELP_STR_SIZE: usize,
+        Ctx,
+        const LINE_SIZE: usize,
+        const HISTORY_SIZE: usize,
+        const PROMPT_SIZE: usize,
+    > Cli<'a, NUM_COMMANDS, HELP_STR_SIZE, Ctx, LINE_SIZE, HISTORY_SIZE, PROMPT_SIZE>
 {
-    pub fn new() -> Cli<'a, NUM_COMMANDS, HELP_STR_SIZE> {
+    pub fn new() -> Self {
+        let mut prompt = String::new();
+        // Left empty if the prompt is made too small for the default one
+        let _ = prompt.push_str("cli> ");
+
         Cli {
-            command_processor: CommandProcessor::new(),
-            prompt: String::from("cli> "),
-            read_buffer: String::new(),
-            command_buffer: String::new(),
+            commands: CommandTable::new(),
+            prompt,
+            line: LineBuffer::new(),
             history_buffer: HistoryBuffer::new(),
             history_buffer_idx: 0,
+            history_ignore_empty: true,
+            history_ignore_duplicates: true,
+            history_ignore_space: true,
+            history_expansion: true,
+            key_decoder: KeyDecoder::new(),
+            keymap: Keymap::default(),
+            completion_pending: false,
+            autosuggestions: true,
+            suggestion_shown: 0,
+            search: None,
+            #[cfg(feature = "history-storage")]
+            history_changed: false,
+            #[cfg(feature = "async")]
+            running_async: false,
+            #[cfg(feature = "async")]
+            async_pending: false,
+            #[cfg(feature = "vi")]
+            vi_normal_keymap: Keymap::new(VI_NORMAL_BINDINGS),
+            #[cfg(feature = "vi")]
+            vi: ViState::new(),
         }
     }
 
@@ -51,564 +394,2990 @@ impl<'a, const NUM_COMMANDS: usize, const HELP_STR_SIZE: usize>
         command: String<32>,
         callback: CommandCallback<'a>,
         help: Option<String<HELP_STR_SIZE>>,
-    ) -> Result<(), CommandProcessorError> {
-        self.command_processor.add_command(command, callback, help)
+    ) -> Result<(), CliError> {
+        self.commands
+            .add(&command, Some(Callback::Simple(callback)), None, help)
     }
 
-    pub fn remove_command(&mut self, command: String<32>) -> Result<(), CommandProcessorError> {
-        self.command_processor.remove_command(command)
+    /// Add a command whose callback is handed the arguments typed after the
+    /// command name
+    pub fn add_command_with_args(
+        &mut self,
+        command: String<32>,
+        callback: ArgsCommandCallback<'a>,
+        help: Option<String<HELP_STR_SIZE>>,
+    ) -> Result<(), CliError> {
+        self.commands
+            .add(&command, Some(Callback::WithArgs(callback)), None, help)
     }
 
-    fn process_command(
+    /// Add a command whose arguments are checked against `schema` before
+    /// the callback runs. Arguments that do not match are reported through
+    /// the writer and the callback is not called.
+    pub fn add_command_with_schema(
         &mut self,
-        writer: Option<&mut (dyn core::fmt::Write + 'a)>,
-    ) -> CommandCallbackReturn<'a> {
-        self.command_processor
-            .process_command(&self.command_buffer, writer)
+        command: String<32>,
+        callback: ArgsCommandCallback<'a>,
+        schema: &'a [ArgSpec<'a>],
+        help: Option<String<HELP_STR_SIZE>>,
+    ) -> Result<(), CliError> {
+        self.commands.add(
+            &command,
+            Some(Callback::WithArgs(callback)),
+            Some(schema),
+            help,
+        )
     }
 
-    pub fn init<T: Read<u8> + Write<u8> + core::fmt::Write + 'a>(
+    /// Add a command whose callback is handed the context passed to
+    /// [`Cli::run_with_context`], with its arguments optionally checked
+    /// against `schema`
+    pub fn add_command_with_context(
         &mut self,
-        serial: &mut T,
+        command: String<32>,
+        callback: ContextCommandCallback<'a, Ctx>,
+        schema: Option<&'a [ArgSpec<'a>]>,
+        help: Option<String<HELP_STR_SIZE>>,
     ) -> Result<(), CliError> {
-        match write!(serial, "\r\n{}", self.prompt) {
-            Ok(_) => (),
-            Err(_) => return Err(CliError::WriteError),
-        };
-
-        Ok(())
+        self.commands.add(
+            &command,
+            Some(Callback::WithContext(callback)),
+            schema,
+            help,
+        )
     }
 
-    pub fn run<T: Read<u8> + Write<u8> + core::fmt::Write + 'a>(
+    /// Add a command backed by a closure, which can capture state of its
+    /// own as well as being handed the context passed to
+    /// [`Cli::run_with_context`]
+    pub fn add_closure(
         &mut self,
-        serial: &mut T,
-    ) -> Result<ReturnCode, CliError> {
-        let result = self.process_serial_loop(serial);
-
-        match result {
-            Err(CliError::ReadError) => (),
-            _ => {
-                self.read_buffer.clear();
-                self.command_buffer.clear();
-            }
-        }
+        command: String<32>,
+        closure: &'a mut CommandClosure<'a, Ctx>,
+        schema: Option<&'a [ArgSpec<'a>]>,
+        help: Option<String<HELP_STR_SIZE>>,
+    ) -> Result<(), CliError> {
+        self.commands
+            .add(&command, Some(Callback::Closure(closure)), schema, help)
+    }
 
-        result
+    /// Add a command run asynchronously by the [`AsyncCommands`] handed to
+    /// [`Cli::run_async_with`], which is told it was entered by `id`
+    #[cfg(feature = "async")]
+    pub fn add_async_command(
+        &mut self,
+        command: String<32>,
+        id: usize,
+        schema: Option<&'a [ArgSpec<'a>]>,
+        help: Option<String<HELP_STR_SIZE>>,
+    ) -> Result<(), CliError> {
+        self.commands
+            .add(&command, Some(Callback::Async(id)), schema, help)
     }
-    fn handle_default_byte<T: Read<u8> + Write<u8> + core::fmt::Write + 'a>(
+
+    /// Add a group of subcommands. Commands are added to the group by
+    /// giving their full path, e.g. `"wifi scan"` after adding `"wifi"`.
+    /// Groups can be nested and each one takes a slot in the command table.
+    pub fn add_group(
         &mut self,
-        serial: &mut T,
-        byte: u8,
+        group: String<32>,
+        help: Option<String<HELP_STR_SIZE>>,
     ) -> Result<(), CliError> {
-        if self.read_buffer.len() < 32 {
-            self.read_buffer
-                .push(byte as char)
-                .map_err(|_| CliError::ReadBufferError)?;
-            serial.write(byte).map_err(|_| CliError::WriteError)?;
-        }
-        if byte.is_ascii_alphanumeric() {
-            self.command_buffer
-                .push(byte as char)
-                .map_err(|_| CliError::CommandBufferError)?;
-        }
-        Ok(())
+        self.commands.add(&group, None, None, help)
     }
 
-    fn handle_history<T: Read<u8> + Write<u8> + core::fmt::Write + 'a>(
+    /// Offer completions for a command's arguments when Tab is pressed.
+    /// Arguments declared as [`ArgKind::Enum`] in the command's schema
+    /// complete from their values without a hook.
+    pub fn add_completion(
         &mut self,
-        serial: &mut T,
-        new_idx: usize,
+        command: String<32>,
+        hook: CompletionHook,
     ) -> Result<(), CliError> {
-        if self.history_buffer.len() > 0 {
-            let prev = self.history_buffer.get(self.history_buffer_idx);
-
-            self.history_buffer_idx = new_idx;
-
-            if let Some(prev) = prev {
-                let current_read_buffer = self.read_buffer.clone();
-
-                self.read_buffer.clear();
-                self.command_buffer.clear();
-                self.read_buffer
-                    .push_str(prev)
-                    .map_err(|_| CliError::ReadBufferError)?;
-                self.command_buffer
-                    .push_str(prev)
-                    .map_err(|_| CliError::CommandBufferError)?;
-
-                for char in current_read_buffer.chars() {
-                    if char.is_ascii_alphanumeric() {
-                        serial.write(b'\x08').map_err(|_| CliError::WriteError)?;
-                    }
-                }
-                serial.write(b' ').map_err(|_| CliError::WriteError)?;
-                for char in self.read_buffer.chars() {
-                    serial.write(char as u8).map_err(|_| CliError::WriteError)?;
-                }
-            }
-        }
+        let index = self
+            .commands
+            .lookup(&command)
+            .ok_or(CliError::UnknownCommand)?;
+        self.commands.get_mut(index).completion = Some(hook);
 
         Ok(())
     }
 
-    fn process_serial_loop<T: Read<u8> + Write<u8> + core::fmt::Write + 'a>(
-        &mut self,
-        serial: &mut T,
-    ) -> Result<ReturnCode, CliError> {
-        loop {
-            let byte = serial.read().map_err(|_| CliError::ReadError)?;
-
-            match byte {
-                // Carriage Return - Time to process the command
-                b'\r' => {
-                    write!(serial, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?;
+    /// Sets the prompt written before each line, `cli> ` by default
+    pub fn set_prompt(&mut self, prompt: &str) -> Result<(), CliError> {
+        let mut new = String::new();
+        new.push_str(prompt).map_err(|_| CliError::PromptTooLong)?;
+        self.prompt = new;
 
-                    let result = self
-                        .process_command(Some(serial))
-                        .map_err(CliError::CommandProcessorError)?;
+        Ok(())
+    }
 
-                    self.history_buffer.write(self.command_buffer.clone());
-                    self.history_buffer_idx = self.history_buffer.len() - 1;
+    /// Show the rest of the most recent history entry starting with the
+    /// line dimmed after the cursor, to be taken with Right or Ctrl+F.
+    /// On by default; turn it off for terminals without SGR support.
+    pub fn set_autosuggestions(&mut self, enabled: bool) {
+        self.autosuggestions = enabled;
+    }
 
-                    write!(serial, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?;
+    /// Leave lines that are empty or only spaces out of the history. On by
+    /// default.
+    pub fn set_history_ignore_empty(&mut self, enabled: bool) {
+        self.history_ignore_empty = enabled;
+    }
 
-                    return Ok(result);
-                }
-                b'\n' => write!(serial, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?,
+    /// Leave a line out of the history when it is the same as the one
+    /// before it. On by default.
+    pub fn set_history_ignore_duplicates(&mut self, enabled: bool) {
+        self.history_ignore_duplicates = enabled;
+    }
 
-                // ASCII Backspace
-                b'\x08' => {
-                    if self.read_buffer.pop().is_some() {
-                        write!(serial, "\x08 \x08").map_err(|_| CliError::WriteError)?;
-                    }
+    /// Leave lines starting with a space out of the history, so that a
+    /// command carrying a secret can be kept out of it. On by default.
+    pub fn set_history_ignore_space(&mut self, enabled: bool) {
+        self.history_ignore_space = enabled;
+    }
 
-                    self.command_buffer.pop();
-                }
+    /// Expand bash style history references such as `!!`, `!3`, `!prefix`
+    /// and `^old^new` before running a line, echoing the line that runs.
+    /// On by default.
+    pub fn set_history_expansion(&mut self, enabled: bool) {
+        self.history_expansion = enabled;
+    }
 
-                // ASCII up arrow
-                b'A' => {
-                    // Check if last two characters were escape and [
+    /// The key bindings, or with the `vi` feature the insert mode bindings
+    pub fn keymap_mut(&mut self) -> &mut Keymap<'a> {
+        &mut self.keymap
+    }
 
-                    let buffer_len = self.read_buffer.len();
+    /// The key bindings for vi normal mode
+    #[cfg(feature = "vi")]
+    pub fn vi_normal_keymap_mut(&mut self) -> &mut Keymap<'a> {
+        &mut self.vi_normal_keymap
+    }
 
-                    let last_two = self.read_buffer.get(buffer_len - 2..buffer_len);
+    /// Remove a command by its full path. Removing a group also removes
+    /// everything in it.
+    pub fn remove_command(&mut self, command: String<32>) -> Result<(), CliError> {
+        self.commands.remove(&command)
+    }
 
-                    if last_two.is_some() && last_two == Some("\x1B[") {
-                        let new_idx = if self.history_buffer_idx > 0 {
-                            self.history_buffer_idx - 1
-                        } else {
-                            0
-                        };
-                        self.handle_history(serial, new_idx)?;
-                    } else {
-                        self.handle_default_byte(serial, byte)?;
-                    }
+    fn process_command(
+        &mut self,
+        context: &mut Ctx,
+        writer: Option<&mut dyn core::fmt::Write>,
+    ) -> Result<ReturnCode, CliError> {
+        let tokens = match Tokens::<LINE_SIZE>::parse(self.line.as_str()) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                if let Some(writer) = writer {
+                    write!(writer, "error: {}", error).map_err(|_| CliError::WriteError)?;
                 }
+                return Err(CliError::TokenizeError(error));
+            }
+        };
 
-                // ASCII down arrow
-                b'B' => {
-                    let buffer_len = self.read_buffer.len();
-
-                    let last_two = self.read_buffer.get(buffer_len - 2..buffer_len);
-
-                    if last_two.is_some() && last_two == Some("\x1B[") {
-                        let new_idx = if self.history_buffer_idx < self.history_buffer.len() - 1 {
-                            self.history_buffer_idx + 1
-                        } else {
-                            self.history_buffer.len() - 1
-                        };
-                        self.handle_history(serial, new_idx)?;
-                    } else {
-                        self.handle_default_byte(serial, byte)?;
-                    }
-                }
+        let words = tokens.args(0);
 
-                // Default case is to echo the character back to the terminal
-                _ => {
-                    self.handle_default_byte(serial, byte)?;
-                }
-            }
+        if words.is_empty() {
+            return Ok(ReturnCode::Success);
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
+        let resolved = self.commands.resolve(words.iter());
 
-    mod serialmock {
-        use embedded_hal::serial::{Read, Write};
-        use heapless::Vec;
+        let index = match resolved.index {
+            Some(index) => index,
+            None if words.get(0) == Some("help") => return self.help(writer, tokens.args(1)),
+            #[cfg(feature = "history-command")]
+            None if words.get(0) == Some("history") => return self.history(writer, tokens.args(1)),
+            None => return Self::unknown_command(writer, words, 1),
+        };
 
-        pub struct SerialMock {
-            read_buffer: Vec<u8, 512>,
-            write_buffer: Vec<u8, 512>,
-            read_ptr: usize,
-            write_ptr: usize,
+        if self.commands.get(index).is_group() {
+            // A group on its own lists its subcommands
+            if resolved.depth < words.len() {
+                return Self::unknown_command(writer, words, resolved.depth + 1);
+            }
+            if let Some(writer) = writer {
+                self.write_commands(writer, resolved.index)?;
+            }
+            return Ok(ReturnCode::Success);
         }
 
-        impl SerialMock {
-            pub fn new() -> SerialMock {
-                SerialMock {
-                    read_buffer: Vec::new(),
-                    write_buffer: Vec::new(),
-                    read_ptr: 0,
-                    write_ptr: 0,
+        let command = self.commands.get_mut(index);
+        let args = tokens.args(resolved.depth);
+
+        let matches = match command.schema {
+            Some(schema) => match schema::match_args(schema, &args) {
+                Ok(matches) => Some((schema, matches)),
+                Err(message) => {
+                    if let Some(writer) = writer {
+                        write!(writer, "error: {}", message).map_err(|_| CliError::WriteError)?;
+                    }
+                    return Err(CliError::InvalidArgument(message.error));
                 }
-            }
+            },
+            None => None,
+        };
 
-            pub fn get_buffer(&mut self) -> &mut Vec<u8, 512> {
-                &mut self.read_buffer
-            }
+        let args = match &matches {
+            Some((schema, matches)) => args.with_matches(schema, matches),
+            None => args,
+        };
 
-            pub fn read_from_write_buffer(&mut self) -> &mut Vec<u8, 512> {
-                &mut self.write_buffer
+        match command.callback.as_mut() {
+            #[cfg(feature = "async")]
+            Some(Callback::Async(_)) if self.running_async => {
+                self.async_pending = true;
+                Ok(ReturnCode::Success)
             }
+            Some(callback) => callback.call(context, &args, writer),
+            None => Ok(ReturnCode::Success),
+        }
+    }
 
-            pub fn write_to_read_buffer(&mut self, bytes: &[u8]) {
-                for byte in bytes {
-                    match self.read_buffer.push(*byte) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
+    /// Reports the first `depth` words of the line as an unknown command
+    fn unknown_command(
+        writer: Option<&mut dyn core::fmt::Write>,
+        words: Args<'_>,
+        depth: usize,
+    ) -> Result<ReturnCode, CliError> {
+        if let Some(writer) = writer {
+            write!(writer, "error: unknown command '").map_err(|_| CliError::WriteError)?;
+            for (i, word) in words.iter().take(depth).enumerate() {
+                if i > 0 {
+                    write!(writer, " ").map_err(|_| CliError::WriteError)?;
                 }
+                write!(writer, "{}", word).map_err(|_| CliError::WriteError)?;
             }
+            write!(writer, "'").map_err(|_| CliError::WriteError)?;
         }
 
-        impl Read<u8> for SerialMock {
-            type Error = ();
+        Err(CliError::UnknownCommand)
+    }
 
-            fn read(&mut self) -> nb::Result<u8, Self::Error> {
-                match self.read_buffer.get(self.read_ptr) {
-                    Some(byte) => {
-                        if self.read_ptr == self.read_buffer.len() - 1 {
-                            self.read_ptr = 0;
-                        } else {
-                            self.read_ptr += 1;
-                        }
-                        Ok(*byte)
-                    }
-                    None => Err(nb::Error::WouldBlock),
+    /// `help` lists the top level commands, `help <group>` the commands in
+    /// a group and `help <command>` just that command
+    fn help(
+        &self,
+        writer: Option<&mut dyn core::fmt::Write>,
+        path: Args<'_>,
+    ) -> Result<ReturnCode, CliError> {
+        let resolved = self.commands.resolve(path.iter());
+
+        if resolved.depth < path.len() {
+            return Self::unknown_command(writer, path, resolved.depth + 1);
+        }
+
+        if let Some(writer) = writer {
+            match resolved.index {
+                Some(index) if !self.commands.get(index).is_group() => {
+                    let command = self.commands.get(index);
+                    Self::write_command(writer, command, command.name.len())?;
                 }
+                parent => self.write_commands(writer, parent)?,
             }
         }
 
-        impl Write<u8> for SerialMock {
-            type Error = ();
+        Ok(ReturnCode::Success)
+    }
 
-            fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
-                match self.write_buffer.push(byte) {
-                    Ok(_) => {
-                        if self.write_ptr == self.write_buffer.len() - 1 {
-                            self.write_ptr = 0;
-                        } else {
-                            self.write_ptr += 1;
-                        }
-                        Ok(())
-                    }
-                    Err(_) => Err(nb::Error::WouldBlock),
+    /// The built-in `history` command: lists the history numbered as `!n`
+    /// refers to it, only the last `count` entries if given, or clears it
+    /// with `-c`
+    #[cfg(feature = "history-command")]
+    fn history(
+        &mut self,
+        writer: Option<&mut dyn core::fmt::Write>,
+        args: Args<'_>,
+    ) -> Result<ReturnCode, CliError> {
+        let matches = match schema::match_args(HISTORY_ARGS, &args) {
+            Ok(matches) => matches,
+            Err(message) => {
+                if let Some(writer) = writer {
+                    write!(writer, "error: {}", message).map_err(|_| CliError::WriteError)?;
                 }
+                return Err(CliError::InvalidArgument(message.error));
+            }
+        };
+        let args = args.with_matches(HISTORY_ARGS, &matches);
+
+        if args.has_flag('c') {
+            self.history_buffer.clear();
+            self.history_buffer_idx = 0;
+            #[cfg(feature = "history-storage")]
+            {
+                self.history_changed = true;
             }
+            return Ok(ReturnCode::Success);
+        }
 
-            fn flush(&mut self) -> nb::Result<(), Self::Error> {
-                Ok(())
+        let len = self.history_buffer.len();
+        let count = args
+            .value::<u32>("count")
+            .map_or(len, |count| len.min(count as usize));
+
+        if let Some(writer) = writer {
+            for (i, entry) in self
+                .history_buffer
+                .oldest_ordered()
+                .enumerate()
+                .skip(len - count)
+            {
+                write!(writer, "\r\n{:>4}  {}", i + 1, entry).map_err(|_| CliError::WriteError)?;
             }
         }
 
-        impl core::fmt::Write for SerialMock {
-            fn write_str(&mut self, s: &str) -> core::fmt::Result {
-                for byte in s.bytes() {
-                    match self.write_buffer.push(byte) {
-                        Ok(_) => {
-                            if self.write_ptr == self.write_buffer.len() - 1 {
-                                self.write_ptr = 0;
-                            } else {
-                                self.write_ptr += 1;
-                            }
-                            ()
-                        }
-                        Err(_) => return Err(core::fmt::Error),
-                    }
-                }
+        Ok(ReturnCode::Success)
+    }
 
-                Ok(())
-            }
+    fn write_commands(
+        &self,
+        writer: &mut dyn core::fmt::Write,
+        parent: Option<usize>,
+    ) -> Result<(), CliError> {
+        let width = self
+            .commands
+            .children(parent)
+            .map(|command| command.name.len())
+            .max()
+            .unwrap_or(0);
+
+        for command in self.commands.children(parent) {
+            Self::write_command(writer, command, width)?;
         }
+
+        Ok(())
     }
 
-    use super::*;
+    fn write_command(
+        writer: &mut dyn core::fmt::Write,
+        command: &command::Command<'a, HELP_STR_SIZE, Ctx>,
+        width: usize,
+    ) -> Result<(), CliError> {
+        write!(
+            writer,
+            "\r\n  {:width$}  {}",
+            command.name,
+            command.help.as_deref().unwrap_or(""),
+            width = width
+        )
+        .map_err(|_| CliError::WriteError)
+    }
 
-    #[test]
-    fn test_init() {
-        let mut cli = Cli::<8, 32>::new();
+    pub fn init<T: core::fmt::Write>(&mut self, serial: &mut T) -> Result<(), CliError> {
+        match write!(serial, "\r\n{}", self.prompt) {
+            Ok(_) => (),
+            Err(_) => return Err(CliError::WriteError),
+        };
 
-        let mut serial = serialmock::SerialMock::new();
+        Ok(())
+    }
 
-        assert!(cli.init(&mut serial).is_ok());
-        assert_eq!(
-            std::string::String::from_utf8(serial.read_from_write_buffer().to_vec()).unwrap(),
-            "\r\ncli> "
-        );
+    /// Forgets the line being typed and where its editing had got to, such
+    /// as half an escape sequence or a search, for a new session to start
+    /// from a blank line. The commands, the settings and the history are
+    /// kept.
+    pub fn reset(&mut self) {
+        self.clear_line();
+        self.key_decoder = KeyDecoder::new();
+        self.completion_pending = false;
+        self.suggestion_shown = 0;
+        #[cfg(feature = "async")]
+        {
+            self.async_pending = false;
+        }
     }
 
-    #[test]
-    fn test_add_remove_command() {
-        let mut cli = Cli::<8, 32>::new();
+    /// Writes the prompt like [`Cli::init`], to the transmit half of a split
+    /// serial port
+    #[cfg(feature = "embedded-hal-02")]
+    pub fn init_split<W: Write<u8>>(&mut self, tx: &mut W) -> Result<(), CliError> {
+        let mut out = SerialWriter::new(tx);
+        self.init(&mut out)
+    }
 
-        cli.add_command(
-            String::from("test"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "Write this to the serial port") {
-                            Ok(_) => (),
+    /// Writes the prompt like [`Cli::init`], to an `embedded-io` writer
+    #[cfg(feature = "embedded-io")]
+    pub fn init_io<W: embedded_io::Write>(&mut self, tx: &mut W) -> Result<(), CliError> {
+        let mut out = IoWriter::new(tx);
+        self.init(&mut out)
+    }
+
+    /// Loads the history saved in `store`, then writes the prompt like
+    /// [`Cli::init`]
+    #[cfg(feature = "history-storage")]
+    pub fn init_with_history<T: core::fmt::Write, S: embedded_storage::nor_flash::NorFlash>(
+        &mut self,
+        serial: &mut T,
+        store: &mut HistoryStore<S>,
+    ) -> Result<(), CliError> {
+        store.load(&mut self.history_buffer)?;
+        self.history_buffer_idx = 0;
+        self.history_changed = false;
+
+        self.init(serial)
+    }
+
+    /// Saves the history to `store` if lines were added since it was loaded
+    /// or last saved. Each save uses flash, so this is best done now and
+    /// then rather than after every line.
+    #[cfg(feature = "history-storage")]
+    pub fn save_history<S: embedded_storage::nor_flash::NorFlash>(
+        &mut self,
+        store: &mut HistoryStore<S>,
+    ) -> Result<(), CliError> {
+        if self.history_changed {
+            store.save(&self.history_buffer)?;
+            self.history_changed = false;
+        }
+
+        Ok(())
+    }
+
+    /// Run the CLI, handing `context` to the callbacks that take one.
+    ///
+    /// Handles the input waiting on `serial` and returns once there is no
+    /// more, or once a line has run, so it can be called from a superloop.
+    #[cfg(feature = "embedded-hal-02")]
+    pub fn run_with_context<T: Read<u8> + Write<u8> + core::fmt::Write>(
+        &mut self,
+        serial: &mut T,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError<<T as Read<u8>>::Error>> {
+        self.run_loop(|serial| serial.read(), serial, context)
+    }
+
+    /// Run the CLI over the two halves of a split serial port, as handed out
+    /// by most HALs, handing `context` to the callbacks that take one. Reads
+    /// from `rx` like [`Cli::run_with_context`], blocking on `tx` to write.
+    #[cfg(feature = "embedded-hal-02")]
+    pub fn run_split_with_context<R: Read<u8>, W: Write<u8>>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError<R::Error>> {
+        let mut out = SerialWriter::new(tx);
+        self.run_loop(|_| rx.read(), &mut out, context)
+    }
+
+    /// Run the CLI over an `embedded-io` port, handing `context` to the
+    /// callbacks that take one. Only reads the input that is ready, so it
+    /// returns like [`Cli::run_with_context`] rather than blocking.
+    #[cfg(feature = "embedded-io")]
+    pub fn run_io_with_context<
+        T: embedded_io::Read + embedded_io::ReadReady + embedded_io::Write,
+    >(
+        &mut self,
+        port: &mut T,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError<<T as embedded_io::ErrorType>::Error>> {
+        let mut out = IoWriter::new(port);
+        self.run_loop(|out| io::read_byte(out.port()), &mut out, context)
+    }
+
+    /// Run the CLI over an `embedded-io` reader and writer, handing
+    /// `context` to the callbacks that take one, like
+    /// [`Cli::run_io_with_context`]
+    #[cfg(feature = "embedded-io")]
+    pub fn run_io_split_with_context<
+        R: embedded_io::Read + embedded_io::ReadReady,
+        W: embedded_io::Write,
+    >(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError<R::Error>> {
+        let mut out = IoWriter::new(tx);
+        self.run_loop(|_| io::read_byte(rx), &mut out, context)
+    }
+
+    /// Run the CLI over an `embedded-io-async` reader and writer, handing
+    /// `context` to the callbacks that take one. Waits for input rather than
+    /// being polled, and returns once a line has run with the return code of
+    /// its command. The end of the input is [`CliError::EndOfInput`].
+    ///
+    /// Commands added with [`Cli::add_async_command`] are run by `commands`
+    /// and write straight to `tx`. Any other output is gathered while a
+    /// byte is handled and written after, up to `OUTPUT_SIZE` bytes, which
+    /// has to be enough for the longest `help` listing. Output that does not
+    /// fit is [`CliError::WriteError`].
+    ///
+    /// ```ignore
+    /// let code = cli.run_async::<256, _, _>(&mut rx, &mut tx).await?;
+    /// ```
+    #[cfg(feature = "async")]
+    pub async fn run_async_with_context<
+        const OUTPUT_SIZE: usize,
+        R: embedded_io_async::Read,
+        W: embedded_io_async::Write,
+        C: AsyncCommands<Ctx>,
+    >(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+        commands: &mut C,
+        context: &mut Ctx,
+    ) -> Result<ReturnCode, CliError<R::Error>> {
+        loop {
+            let mut byte = [0];
+            match rx.read(&mut byte).await {
+                Ok(0) => return Err(CliError::EndOfInput),
+                Ok(_) => (),
+                Err(error) => return Err(CliError::ReadError(error)),
+            }
+
+            let mut out = AsyncWriter::<OUTPUT_SIZE>::new();
+            self.running_async = true;
+            let result = self.feed_with_context(byte[0], &mut out, context);
+            self.running_async = false;
+            let pending = core::mem::take(&mut self.async_pending);
+
+            let mut written = out.write_to(tx).await;
+
+            if pending {
+                let mut result = self.run_async_command(tx, commands, context).await;
+
+                if let Ok(code) = result {
+                    result = self.finish_line(&mut out).map(|_| code);
+                }
+                self.clear_line();
+
+                if written.is_ok() {
+                    written = out.write_to(tx).await;
+                }
+                written.map_err(CliError::widen)?;
+                return result.map_err(CliError::widen);
+            }
+
+            written.map_err(CliError::widen)?;
+            if let RunStatus::CommandExecuted(code) = result.map_err(CliError::widen)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Runs the async command the line names, once `process_command` has
+    /// checked the line
+    #[cfg(feature = "async")]
+    async fn run_async_command<W: embedded_io_async::Write, C: AsyncCommands<Ctx>>(
+        &mut self,
+        tx: &mut W,
+        commands: &mut C,
+        context: &mut Ctx,
+    ) -> Result<ReturnCode, CliError> {
+        let tokens =
+            Tokens::<LINE_SIZE>::parse(self.line.as_str()).map_err(CliError::TokenizeError)?;
+        let resolved = self.commands.resolve(tokens.args(0).iter());
+        let command = self
+            .commands
+            .get(resolved.index.ok_or(CliError::UnknownCommand)?);
+
+        let id = match command.callback {
+            Some(Callback::Async(id)) => id,
+            _ => return Err(CliError::UnknownCommand),
+        };
+
+        let args = tokens.args(resolved.depth);
+        let matches = match command.schema {
+            Some(schema) => Some((
+                schema,
+                schema::match_args(schema, &args)
+                    .map_err(|message| CliError::InvalidArgument(message.error))?,
+            )),
+            None => None,
+        };
+        let args = match &matches {
+            Some((schema, matches)) => args.with_matches(schema, matches),
+            None => args,
+        };
+
+        commands
+            .call(id, context, &args, tx)
+            .await
+            .map_err(CliError::CommandProcessorError)
+    }
+
+    /// Run the CLI over a `std` reader and writer, such as a terminal in raw
+    /// mode or a TCP stream, handing `context` to the callbacks that take
+    /// one. Writes the prompt, then handles the input until it ends or Ctrl+D
+    /// is pressed on an empty line. Lines that fail are reported to the user
+    /// and do not stop it.
+    #[cfg(feature = "std")]
+    pub fn run_std_with_context<R: std::io::Read, W: std::io::Write>(
+        &mut self,
+        mut input: R,
+        mut output: W,
+        context: &mut Ctx,
+    ) -> Result<(), CliError<std::io::Error>> {
+        let mut out = std::string::String::new();
+        let mut result = self.init(&mut out).map(|_| RunStatus::Idle);
+        let mut byte = [0];
+
+        loop {
+            output
+                .write_all(out.as_bytes())
+                .and_then(|_| output.flush())
+                .map_err(|_| CliError::WriteError)?;
+            out.clear();
+
+            match result {
+                Err(CliError::EndOfInput) => return Ok(()),
+                Err(CliError::WriteError) => return Err(CliError::WriteError),
+                _ => (),
+            }
+
+            result = match input.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Ok(_) => self.feed_with_context(byte[0], &mut out, context),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(CliError::ReadError(error)),
+            };
+        }
+    }
+
+    /// Feeds the bytes `read` hands out until it would block or a line has
+    /// run. `read` is handed `out`, for ports that also do the writing.
+    #[cfg(any(feature = "embedded-hal-02", feature = "embedded-io"))]
+    fn run_loop<E, W: core::fmt::Write>(
+        &mut self,
+        mut read: impl FnMut(&mut W) -> nb::Result<u8, E>,
+        out: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError<E>> {
+        let mut status = RunStatus::Idle;
+
+        loop {
+            let byte = match read(out) {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Ok(status),
+                Err(nb::Error::Other(error)) => return Err(CliError::ReadError(error)),
+            };
+
+            status = self
+                .feed_with_context(byte, out, context)
+                .map_err(CliError::widen)?;
+            if let RunStatus::CommandExecuted(_) = status {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Handles a byte of input, handing `context` to the callbacks that
+    /// take one. The echo, the line editing and the output of commands go
+    /// to `out`.
+    ///
+    /// For input that does not come from an `embedded-hal` serial port, such
+    /// as a DMA ring buffer, an interrupt fed queue or RTT.
+    pub fn feed_with_context<W: core::fmt::Write>(
+        &mut self,
+        byte: u8,
+        out: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError> {
+        let result = self.process_byte(byte, out, context);
+
+        match result {
+            // The line is kept to carry on with
+            Ok(RunStatus::Idle | RunStatus::LineEdited) | Err(CliError::LineTooLong) => (),
+            _ => self.clear_line(),
+        }
+
+        result
+    }
+
+    /// Handles `bytes` like [`Cli::feed_with_context`] until a line runs or
+    /// a byte fails. Returns the number of bytes handled, for the caller to
+    /// go on with the rest, along with the result of the last one:
+    /// [`RunStatus::LineEdited`] if they were all handled without finishing
+    /// a line, or [`RunStatus::Idle`] if there were none.
+    pub fn feed_slice_with_context<W: core::fmt::Write>(
+        &mut self,
+        bytes: &[u8],
+        out: &mut W,
+        context: &mut Ctx,
+    ) -> (usize, Result<RunStatus, CliError>) {
+        for (index, &byte) in bytes.iter().enumerate() {
+            match self.feed_with_context(byte, out, context) {
+                Ok(RunStatus::Idle | RunStatus::LineEdited) => (),
+                result => return (index + 1, result),
+            }
+        }
+
+        let status = match bytes.is_empty() {
+            true => RunStatus::Idle,
+            false => RunStatus::LineEdited,
+        };
+        (bytes.len(), Ok(status))
+    }
+
+    /// Replaces the line with the history entry `new_idx` entries back,
+    /// 1 being the most recent
+    fn handle_history(
+        &mut self,
+        out: &mut dyn core::fmt::Write,
+        new_idx: usize,
+    ) -> Result<(), CliError> {
+        self.history_buffer_idx = new_idx;
+
+        let index = self.history_buffer.len().saturating_sub(new_idx);
+        if let Some(entry) = self.history_buffer.oldest_ordered().nth(index) {
+            self.line
+                .replace(entry, out)
+                .map_err(|_| CliError::WriteError)?;
+        }
+
+        Ok(())
+    }
+
+    fn process_byte<W: core::fmt::Write>(
+        &mut self,
+        byte: u8,
+        out: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError> {
+        let key = match self.key_decoder.decode(byte) {
+            Some(key) => key,
+            None => return Ok(RunStatus::LineEdited),
+        };
+
+        // A lone Esc is decoded along with the key after it
+        let result = self.process_key(key, out, context);
+        match (result, self.key_decoder.next_key()) {
+            (Ok(RunStatus::LineEdited), Some(key)) => self.process_key(key, out, context),
+            (result, _) => result,
+        }
+    }
+
+    fn process_key<W: core::fmt::Write>(
+        &mut self,
+        key: Key,
+        out: &mut W,
+        context: &mut Ctx,
+    ) -> Result<RunStatus, CliError> {
+        let action = if self.search.is_some() {
+            // Keys other than those driving the search end it and are
+            // then handled as usual
+            match self.search_key(key, out) {
+                Ok(Some(action)) => action,
+                Ok(None) => return Ok(RunStatus::LineEdited),
+                Err(_) => return Err(CliError::WriteError),
+            }
+        } else {
+            // In vi insert mode Esc followed by a key arrives as Alt and
+            // the key, which switches to normal mode and then handles the
+            // key
+            #[cfg(feature = "vi")]
+            let key = match key {
+                Key::Alt(c)
+                    if self.vi.mode == Mode::Insert && self.keymap.lookup(key).is_none() =>
+                {
+                    // The cursor moves back, so the suggestion has to go
+                    // first or clearing it would clear the last character
+                    self.hide_suggestion(out)
+                        .map_err(|_| CliError::WriteError)?;
+                    self.edit_line(Action::ViNormalMode, out)
+                        .map_err(|_| CliError::WriteError)?;
+                    Key::Char(c)
+                }
+                _ => key,
+            };
+
+            match self.action(key) {
+                Some(action) => action,
+                None => return Ok(RunStatus::LineEdited),
+            }
+        };
+
+        let list_completions = core::mem::replace(&mut self.completion_pending, false);
+
+        // Typing the next character of the suggestion leaves the rest
+        // of it on screen as it is
+        if let Action::Insert(c) = action {
+            if self.suggestion_shown > 0 && self.suggestion().is_some_and(|s| s.starts_with(c)) {
+                self.edit_line(action, out)
+                    .map_err(|_| CliError::WriteError)?;
+                self.suggestion_shown -= 1;
+                return Ok(RunStatus::LineEdited);
+            }
+        }
+
+        self.hide_suggestion(out)
+            .map_err(|_| CliError::WriteError)?;
+
+        match action {
+            // Carriage Return - Time to process the command
+            Action::AcceptLine => {
+                let result = match self.history_expansion {
+                    true => self.expand_line(out),
+                    false => Ok(()),
+                };
+
+                let result = match result {
+                    // The line is kept to be edited
+                    Err(CliError::LineTooLong) => return Err(CliError::LineTooLong),
+                    Err(error) => Err(error),
+                    Ok(()) => write!(out, "\r\n{}", self.prompt)
+                        .map_err(|_| CliError::WriteError)
+                        .and_then(|_| self.process_command(context, Some(out))),
+                };
+
+                // The line is kept for `run_async_with_context` to run the
+                // command and finish it
+                #[cfg(feature = "async")]
+                if self.async_pending {
+                    return Ok(RunStatus::LineEdited);
+                }
+
+                // A line that fails is still recorded and followed by a
+                // prompt, after its error, unless its history reference
+                // failed, which would only fail again when recalled
+                match result {
+                    Err(CliError::HistoryExpansion) => {
+                        write!(out, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?
+                    }
+                    _ => self.finish_line(out)?,
+                }
+
+                return result.map(RunStatus::CommandExecuted);
+            }
+
+            Action::LineFeed => {
+                write!(out, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?
+            }
+
+            Action::CancelLine => {
+                write!(out, "^C\r\n{}", self.prompt).map_err(|_| CliError::WriteError)?;
+                self.clear_line();
+            }
+
+            // Logs out on an empty line, like a shell
+            Action::DeleteOrEndOfInput if self.line.is_empty() => return Err(CliError::EndOfInput),
+
+            // Keeps the line being edited
+            Action::ClearScreen => {
+                write!(out, "\x1B[H\x1B[2J{}", self.prompt)
+                    .and_then(|_| self.line.redraw(out))
+                    .map_err(|_| CliError::WriteError)?;
+            }
+
+            Action::Complete => {
+                self.completion_pending = self
+                    .complete(list_completions, out)
+                    .map_err(|_| CliError::WriteError)?;
+            }
+
+            Action::HistoryPrev if !self.history_buffer.is_empty() => {
+                let new_idx = (self.history_buffer_idx + 1).min(self.history_buffer.len());
+                self.handle_history(out, new_idx)?;
+            }
+
+            // Stops at the most recent entry
+            Action::HistoryNext if self.history_buffer_idx > 1 => {
+                let new_idx = self.history_buffer_idx - 1;
+                self.handle_history(out, new_idx)?;
+            }
+
+            Action::HistoryPrev | Action::HistoryNext => (),
+
+            Action::ReverseSearch => {
+                let search = Search::new(self.line.as_str());
+                search.draw(None, out).map_err(|_| CliError::WriteError)?;
+                self.search = Some(search);
+            }
+
+            _ => self
+                .edit_line(action, out)
+                .map_err(|_| CliError::WriteError)?,
+        }
+
+        if self.line.take_overflow() {
+            out.write_char('\x07').map_err(|_| CliError::WriteError)?;
+            return Err(CliError::LineTooLong);
+        }
+
+        #[cfg(feature = "vi")]
+        self.vi
+            .settle(&mut self.line, out)
+            .map_err(|_| CliError::WriteError)?;
+
+        self.show_suggestion(out)
+            .map_err(|_| CliError::WriteError)?;
+
+        Ok(RunStatus::LineEdited)
+    }
+
+    /// Expands history references in the line, echoing it if anything was
+    /// expanded
+    fn expand_line(&mut self, out: &mut dyn core::fmt::Write) -> Result<(), CliError> {
+        let mut expanded: String<LINE_SIZE> = String::new();
+
+        match expand(self.line.as_str(), &self.history_buffer, &mut expanded) {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                self.line.set(&expanded);
+                write!(out, "\r\n{}", expanded).map_err(|_| CliError::WriteError)
+            }
+            // Like typing too much, the line is kept to be edited
+            Err(ExpandError::TooLong) => {
+                out.write_char('\x07').map_err(|_| CliError::WriteError)?;
+                Err(CliError::LineTooLong)
+            }
+            Err(error) => {
+                write!(out, "\r\n{}error: {}", self.prompt, error)
+                    .map_err(|_| CliError::WriteError)?;
+                Err(CliError::HistoryExpansion)
+            }
+        }
+    }
+
+    /// Records the line that has run in the history and writes the prompt
+    /// for the next one
+    fn finish_line(&mut self, out: &mut dyn core::fmt::Write) -> Result<(), CliError> {
+        if self.records(self.line.as_str()) {
+            self.history_buffer.write(String::from(self.line.as_str()));
+            #[cfg(feature = "history-storage")]
+            {
+                self.history_changed = true;
+            }
+        }
+
+        write!(out, "\r\n{}", self.prompt).map_err(|_| CliError::WriteError)
+    }
+
+    /// Whether `line` goes in the history under the history options
+    fn records(&self, line: &str) -> bool {
+        let empty = line.trim_start_matches(' ').is_empty();
+        let duplicate = self
+            .history_buffer
+            .recent()
+            .is_some_and(|recent| recent == line);
+
+        let ignored = (self.history_ignore_empty && empty)
+            || (self.history_ignore_space && line.starts_with(' '))
+            || (self.history_ignore_duplicates && duplicate);
+
+        !ignored
+    }
+
+    /// The rest of the most recent history entry starting with the line,
+    /// offered while the cursor is at the end of the line
+    fn suggestion(&self) -> Option<&str> {
+        let line = self.line.as_str();
+        if !self.autosuggestions || self.search.is_some() || line.is_empty() || !self.line.at_end()
+        {
+            return None;
+        }
+
+        self.history_buffer
+            .oldest_ordered()
+            .filter(|entry| entry.len() > line.len() && entry.starts_with(line))
+            .last()
+            .map(|entry| &entry[line.len()..])
+    }
+
+    /// Shows the suggestion dimmed after the cursor
+    fn show_suggestion(&mut self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        if let Some(suggestion) = self.suggestion() {
+            write!(out, "\x1B[2m{}\x1B[0m", suggestion)?;
+            cursor_left(out, suggestion.len())?;
+            self.suggestion_shown = suggestion.len();
+        }
+
+        Ok(())
+    }
+
+    fn hide_suggestion(&mut self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        if core::mem::replace(&mut self.suggestion_shown, 0) > 0 {
+            // The suggestion is only shown with the cursor at the end of
+            // the line
+            out.write_str("\x1B[K")?;
+        }
+
+        Ok(())
+    }
+
+    /// Completes the command name or argument before the cursor. With several
+    /// candidates only their common start is completed, unless `list` is
+    /// set, in which case they are listed and the line is redrawn below.
+    /// Returns whether there were several candidates.
+    fn complete(
+        &mut self,
+        list: bool,
+        out: &mut dyn core::fmt::Write,
+    ) -> Result<bool, core::fmt::Error> {
+        let before = &self.line.as_str()[..self.line.cursor()];
+        let start = before.rfind(' ').map_or(0, |space| space + 1);
+        let prefix = &before[start..];
+        let mut completions = Completions::new(prefix);
+
+        // Quotes left open before the cursor leave nothing to complete
+        if let Ok(tokens) = Tokens::<LINE_SIZE>::parse(before) {
+            let words = tokens.args(0);
+            let typed = words.len().saturating_sub(usize::from(!prefix.is_empty()));
+            let resolved = self.commands.resolve(words.iter().take(typed));
+
+            match resolved.index {
+                Some(index) if !self.commands.get(index).is_group() => {
+                    let command = self.commands.get(index);
+                    let args = tokens.args(resolved.depth);
+                    let current = typed - resolved.depth;
+
+                    if let Some(schema) = command.schema {
+                        complete_arg(schema, &args, current, &mut completions);
+                    }
+                    if let Some(hook) = command.completion {
+                        hook(&args, current, &mut completions);
+                    }
+                }
+                parent if resolved.depth == typed => {
+                    for command in self.commands.children(parent) {
+                        completions.push(&command.name);
+                    }
+                    if parent.is_none() {
+                        completions.push("help");
+                        #[cfg(feature = "history-command")]
+                        completions.push("history");
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let candidates = completions.len();
+        if candidates > 1 && list {
+            completions.write_columns(out)?;
+            write!(out, "\r\n{}", self.prompt)?;
+            self.line.redraw(out)?;
+            return Ok(true);
+        }
+
+        let mut completion: String<LINE_SIZE> = String::new();
+        let _ = completion.push_str(&completions.common_prefix()[prefix.len()..]);
+        if candidates == 1 {
+            let _ = completion.push(' ');
+        }
+
+        if completion.is_empty() {
+            // Nothing to add, ring the bell
+            out.write_char('\x07')?;
+        } else {
+            self.line.insert_str(&completion, out)?;
+        }
+
+        Ok(candidates > 1)
+    }
+
+    /// Handles a key during a reverse history search. Returns the action
+    /// to go on with when the key ends the search.
+    fn search_key(
+        &mut self,
+        key: Key,
+        out: &mut dyn core::fmt::Write,
+    ) -> Result<Option<Action>, core::fmt::Error> {
+        let action = self.action(key);
+        match (key, action) {
+            // Esc arrives together with the key after it
+            (Key::Alt(c), _) => {
+                self.end_search(true, out)?;
+                return Ok(self.action(Key::Char(c)));
+            }
+            // Esc gives up the search like Ctrl+G, whatever it is bound to
+            (Key::Escape, _) | (_, Some(Action::Abort)) => {
+                self.end_search(true, out)?;
+                return Ok(None);
+            }
+            (_, None) => return Ok(None),
+            (_, Some(Action::Insert(_) | Action::Backspace | Action::ReverseSearch)) => (),
+            (_, action) => {
+                self.end_search(false, out)?;
+                return Ok(action);
+            }
+        }
+
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return Ok(action),
+        };
+        let history = self.history_buffer.oldest_ordered().map(String::as_str);
+        match action {
+            Some(Action::Insert(c)) => search.push(c, history),
+            Some(Action::Backspace) => search.pop(history),
+            _ => search.older(history),
+        }
+
+        let entry = search
+            .found()
+            .and_then(|index| self.history_buffer.oldest_ordered().nth(index));
+        search.draw(entry.map(String::as_str), out)?;
+
+        Ok(None)
+    }
+
+    /// Ends the reverse history search, redrawing the prompt with the entry
+    /// found or, if `restore` is set, the line as it was before the search
+    fn end_search(&mut self, restore: bool, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        let search = match self.search.take() {
+            Some(search) => search,
+            None => return Ok(()),
+        };
+
+        let entry = match search.found() {
+            Some(index) if !restore => self.history_buffer.oldest_ordered().nth(index),
+            _ => None,
+        };
+        self.line
+            .set(entry.map_or(search.original(), String::as_str));
+
+        write!(out, "\r\x1B[K{}", self.prompt)?;
+        self.line.redraw(out)
+    }
+
+    /// The action bound to `key` in the current mode
+    fn action(&self, key: Key) -> Option<Action> {
+        #[cfg(feature = "vi")]
+        if self.vi.mode == Mode::Normal {
+            return self.vi_normal_keymap.lookup(key);
+        }
+
+        self.keymap.lookup(key)
+    }
+
+    /// Applies an action that only changes the line being edited
+    fn edit_line(&mut self, action: Action, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        if !matches!(action, Action::Yank | Action::YankPop) {
+            self.line.end_yank();
+        }
+
+        #[cfg(feature = "vi")]
+        if self.vi.apply(action, &mut self.line, out)? {
+            return Ok(());
+        }
+
+        match action {
+            Action::Insert(c) => self.line.insert(c, out).map(|_| ()),
+            Action::Backspace => self.line.backspace(out),
+            Action::Delete | Action::DeleteOrEndOfInput => self.line.delete(out),
+            Action::Left => self.line.move_by(Motion::Left, out),
+            // Right at the end of the line takes the suggestion
+            Action::Right => match self.suggestion() {
+                Some(suggestion) => {
+                    let suggestion: String<LINE_SIZE> = String::from(suggestion);
+                    self.line.insert_str(&suggestion, out).map(|_| ())
+                }
+                None => self.line.move_by(Motion::Right, out),
+            },
+            Action::Home => self.line.move_by(Motion::Home, out),
+            Action::End => self.line.move_by(Motion::End, out),
+            Action::WordLeft => self.line.move_by(Motion::WordStart, out),
+            Action::WordRight => self.line.move_by(Motion::WordEnd, out),
+            Action::KillToEnd => self.line.kill_by(Motion::End, out),
+            Action::KillToStart => self.line.kill_by(Motion::Home, out),
+            Action::KillWord => self.line.kill_by(Motion::WordStart, out),
+            Action::Yank => self.line.yank(out),
+            Action::YankPop => self.line.yank_pop(out),
+            _ => Ok(()),
+        }
+    }
+
+    fn clear_line(&mut self) {
+        self.line.clear();
+        self.history_buffer_idx = 0;
+        self.search = None;
+        #[cfg(feature = "vi")]
+        {
+            self.vi = ViState::new();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "embedded-hal-02"))]
+mod tests {
+
+    mod serialmock {
+        use embedded_hal::serial::{Read, Write};
+        use heapless::Vec;
+
+        pub struct SerialMock {
+            read_buffer: Vec<u8, 512>,
+            write_buffer: Vec<u8, 512>,
+            read_ptr: usize,
+            write_ptr: usize,
+            read_error: bool,
+        }
+
+        impl SerialMock {
+            pub fn new() -> SerialMock {
+                SerialMock {
+                    read_buffer: Vec::new(),
+                    write_buffer: Vec::new(),
+                    read_ptr: 0,
+                    write_ptr: 0,
+                    read_error: false,
+                }
+            }
+
+            pub fn get_buffer(&mut self) -> &mut Vec<u8, 512> {
+                &mut self.read_buffer
+            }
+
+            pub fn read_from_write_buffer(&mut self) -> &mut Vec<u8, 512> {
+                &mut self.write_buffer
+            }
+
+            /// Makes the next read fail, like a UART overrun
+            pub fn fail_next_read(&mut self) {
+                self.read_error = true;
+            }
+
+            pub fn write_to_read_buffer(&mut self, bytes: &[u8]) {
+                for byte in bytes {
+                    match self.read_buffer.push(*byte) {
+                        Ok(_) => (),
+                        Err(_) => (),
+                    }
+                }
+            }
+        }
+
+        impl Read<u8> for SerialMock {
+            type Error = ();
+
+            fn read(&mut self) -> nb::Result<u8, Self::Error> {
+                if core::mem::replace(&mut self.read_error, false) {
+                    return Err(nb::Error::Other(()));
+                }
+
+                match self.read_buffer.get(self.read_ptr) {
+                    Some(byte) => {
+                        self.read_ptr += 1;
+                        Ok(*byte)
+                    }
+                    None => Err(nb::Error::WouldBlock),
+                }
+            }
+        }
+
+        impl Write<u8> for SerialMock {
+            type Error = ();
+
+            fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                match self.write_buffer.push(byte) {
+                    Ok(_) => {
+                        if self.write_ptr == self.write_buffer.len() - 1 {
+                            self.write_ptr = 0;
+                        } else {
+                            self.write_ptr += 1;
+                        }
+                        Ok(())
+                    }
+                    Err(_) => Err(nb::Error::WouldBlock),
+                }
+            }
+
+            fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                Ok(())
+            }
+        }
+
+        impl core::fmt::Write for SerialMock {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                for byte in s.bytes() {
+                    match self.write_buffer.push(byte) {
+                        Ok(_) => {
+                            if self.write_ptr == self.write_buffer.len() - 1 {
+                                self.write_ptr = 0;
+                            } else {
+                                self.write_ptr += 1;
+                            }
+                            ()
+                        }
+                        Err(_) => return Err(core::fmt::Error),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// An in-memory async pipe, and just enough of an executor to poll the
+    /// futures waiting on it by hand
+    #[cfg(feature = "async")]
+    mod pipe {
+        use core::cell::{Cell, RefCell};
+        use core::convert::Infallible;
+        use core::future::{poll_fn, Future};
+        use core::pin::Pin;
+        use core::task::{Context, Poll, Waker};
+        use embedded_io_async::{ErrorType, Read, Write};
+        use std::collections::VecDeque;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::task::Wake;
+
+        #[derive(Default)]
+        pub struct Pipe {
+            bytes: RefCell<VecDeque<u8>>,
+            closed: Cell<bool>,
+            waker: RefCell<Option<Waker>>,
+        }
+
+        impl Pipe {
+            pub fn send(&self, bytes: &[u8]) {
+                self.bytes.borrow_mut().extend(bytes);
+                self.wake();
+            }
+
+            pub fn close(&self) {
+                self.closed.set(true);
+                self.wake();
+            }
+
+            pub fn received(&self) -> std::string::String {
+                let bytes: std::vec::Vec<u8> = self.bytes.borrow_mut().drain(..).collect();
+                std::string::String::from_utf8(bytes).unwrap()
+            }
+
+            fn wake(&self) {
+                if let Some(waker) = self.waker.borrow_mut().take() {
+                    waker.wake();
+                }
+            }
+        }
+
+        impl ErrorType for &Pipe {
+            type Error = Infallible;
+        }
+
+        impl Read for &Pipe {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+                poll_fn(|cx| match self.bytes.borrow_mut().pop_front() {
+                    Some(byte) => {
+                        buf[0] = byte;
+                        Poll::Ready(Ok(1))
+                    }
+                    None if self.closed.get() => Poll::Ready(Ok(0)),
+                    None => {
+                        *self.waker.borrow_mut() = Some(cx.waker().clone());
+                        Poll::Pending
+                    }
+                })
+                .await
+            }
+        }
+
+        impl Write for &Pipe {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+                self.bytes.borrow_mut().extend(buf);
+                Ok(buf.len())
+            }
+        }
+
+        /// Returns `Pending` once, waking the task straight away
+        pub async fn yield_now() {
+            let mut yielded = false;
+            poll_fn(|cx| {
+                if yielded {
+                    return Poll::Ready(());
+                }
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+            .await
+        }
+
+        #[derive(Default)]
+        pub struct Executor {
+            woken: Arc<Woken>,
+        }
+
+        #[derive(Default)]
+        struct Woken(AtomicBool);
+
+        impl Wake for Woken {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        impl Executor {
+            pub fn poll<F: Future>(&self, future: Pin<&mut F>) -> Poll<F::Output> {
+                self.woken.0.store(false, Ordering::SeqCst);
+                let waker = Waker::from(self.woken.clone());
+                future.poll(&mut Context::from_waker(&waker))
+            }
+
+            /// The future last polled asked to be polled again
+            pub fn woken(&self) -> bool {
+                self.woken.0.load(Ordering::SeqCst)
+            }
+        }
+    }
+
+    use super::*;
+
+    #[test]
+    fn test_init() {
+        let mut cli = Cli::<8, 32>::new();
+
+        let mut serial = serialmock::SerialMock::new();
+
+        assert!(cli.init(&mut serial).is_ok());
+        assert_eq!(
+            std::string::String::from_utf8(serial.read_from_write_buffer().to_vec()).unwrap(),
+            "\r\ncli> "
+        );
+    }
+
+    #[test]
+    fn test_add_remove_command() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("test"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "Write this to the serial port") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test command")),
+        )
+        .unwrap();
+
+        assert!(cli.remove_command(String::from("test")).is_ok());
+    }
+
+    #[test]
+    fn test_remove_unknown_command() {
+        let mut cli = Cli::<8, 32>::new();
+
+        assert!(cli.remove_command(String::from("test")).is_err());
+    }
+
+    #[test]
+    fn test_add_command_too_many() {
+        let mut cli = Cli::<2, 32>::new();
+
+        cli.add_command(
+            String::from("test"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "Write this to the serial port") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test command")),
+        )
+        .unwrap();
+
+        cli.add_command(
+            String::from("test2"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "test2") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test2 command")),
+        )
+        .unwrap();
+
+        assert!(cli
+            .add_command(
+                String::from("test3"),
+                |writer| {
+                    match writer {
+                        Some(writer) => {
+                            match write!(writer, "test3") {
+                                Ok(_) => (),
+                                Err(_) => return Err(CommandProcessorError::WriteError),
+                            };
+                        }
+                        None => (),
+                    };
+
+                    Ok(ReturnCode::Success)
+                },
+                Some(String::from("test3 command")),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_process_command() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("test"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "Write this to the serial port") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test command")),
+        )
+        .unwrap();
+
+        cli.add_command(
+            String::from("test2"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "test2") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test2 command")),
+        )
+        .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+
+        let test_str = "test\r\ntest2\r\n";
+
+        serial.write_to_read_buffer(test_str.as_bytes());
+
+        assert!(cli.init(&mut serial).is_ok());
+
+        let result = cli.run(&mut serial);
+
+        assert!(result.is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+
+        // Convert bytes to string
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "\r\ncli> test\r\ncli> Write this to the serial port\r\ncli> "
+        );
+
+        let bytes = serial.get_buffer();
+
+        // Convert bytes to string
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "test\r\ntest2\r\n");
+    }
+
+    #[test]
+    fn test_history() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("test"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "test") {
+                            Ok(_) => (),
+                            Err(_) => return Err(CommandProcessorError::WriteError),
+                        };
+                    }
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test command")),
+        )
+        .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+
+        // test string with ascii up arrow
+        let test_str = "test\r\n\x1B[A\r\n";
+
+        serial.write_to_read_buffer(test_str.as_bytes());
+
+        assert!(cli.init(&mut serial).is_ok());
+
+        let result = cli.run(&mut serial);
+
+        assert!(result.is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+
+        // Convert bytes to string
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "\r\ncli> test\r\ncli> test\r\ncli> ");
+
+        let bytes = serial.get_buffer();
+
+        // Convert bytes to string
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "test\r\n\x1b[A\r\n");
+    }
+
+    #[test]
+    fn test_backspace() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("test"),
+            |writer| {
+                match writer {
+                    Some(writer) => {
+                        match write!(writer, "hello") {
+                            Ok(_) => (),
                             Err(_) => return Err(CommandProcessorError::WriteError),
                         };
                     }
-                    None => (),
-                };
+                    None => (),
+                };
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("test command")),
+        )
+        .unwrap();
+
+        let test_str = "testt\x08\r\n";
+
+        let mut serial = serialmock::SerialMock::new();
+
+        serial.write_to_read_buffer(test_str.as_bytes());
+
+        assert!(cli.init(&mut serial).is_ok());
+
+        let result = cli.run(&mut serial);
+
+        assert!(result.is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+
+        // Convert bytes to string
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "\r\ncli> testt\x08 \x08\r\ncli> hello\r\ncli> ");
+    }
+
+    #[test]
+    fn test_command_with_args() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command_with_args(
+            String::from("led"),
+            |args, writer| {
+                if let Some(writer) = writer {
+                    for arg in args.iter() {
+                        write!(writer, "[{}]", arg)
+                            .map_err(|_| CommandProcessorError::WriteError)?;
+                    }
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("led command")),
+        )
+        .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+
+        serial.write_to_read_buffer(b"led 3 \"fast on\"\r");
+
+        assert!(cli.init(&mut serial).is_ok());
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "\r\ncli> led 3 \"fast on\"\r\ncli> [3][fast on]\r\ncli> "
+        );
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut cli = Cli::<8, 32>::new();
+
+        let mut serial = serialmock::SerialMock::new();
+
+        serial.write_to_read_buffer(b"nope\r");
+
+        assert!(cli.init(&mut serial).is_ok());
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::UnknownCommand)
+        ));
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "\r\ncli> nope\r\ncli> error: unknown command 'nope'\r\ncli> "
+        );
+    }
+
+    #[test]
+    fn test_command_with_schema() {
+        const PWM_ARGS: &[ArgSpec] = &[
+            ArgSpec::required("channel", ArgKind::U8),
+            ArgSpec::required("freq", ArgKind::int(1, 1000)),
+        ];
+
+        let mut cli = Cli::<8, 32>::new();
+        cli.set_autosuggestions(false);
+
+        cli.add_command_with_schema(
+            String::from("pwm"),
+            |args, writer| {
+                let channel: u8 = args.value("channel").unwrap();
+                let freq: u32 = args.value("freq").unwrap();
+
+                if let Some(writer) = writer {
+                    write!(writer, "{}@{}", channel, freq)
+                        .map_err(|_| CommandProcessorError::WriteError)?;
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            PWM_ARGS,
+            Some(String::from("pwm command")),
+        )
+        .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"pwm 2 0x10\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "pwm 2 0x10\r\ncli> 2@16\r\ncli> ");
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"pwm 2 2000\r");
+
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::InvalidArgument(ArgError {
+                index: 2,
+                reason: ArgErrorReason::Invalid
+            }))
+        ));
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "pwm 2 2000\r\ncli> error: arg 2 'freq': expected integer 1..=1000\r\ncli> "
+        );
+
+        // The line is still recorded, to be recalled and fixed
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\x1B[A");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "pwm 2 2000\x1B[K");
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let mut cli = Cli::<8, 32>::new();
+        cli.set_autosuggestions(false);
+
+        cli.add_group(String::from("wifi"), Some(String::from("wifi commands")))
+            .unwrap();
+        cli.add_command_with_args(
+            String::from("wifi connect"),
+            |args, writer| {
+                if let Some(writer) = writer {
+                    write!(writer, "connecting to {}", args.get(0).unwrap_or(""))
+                        .map_err(|_| CommandProcessorError::WriteError)?;
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            Some(String::from("join a network")),
+        )
+        .unwrap();
+        cli.add_command(
+            String::from("wifi scan"),
+            |_| Ok(ReturnCode::Success),
+            Some(String::from("list networks")),
+        )
+        .unwrap();
+        cli.add_command(String::from("reset"), |_| Ok(ReturnCode::Success), None)
+            .unwrap();
+
+        let cases = [
+            ("wifi connect home\r", "connecting to home"),
+            (
+                "wifi\r",
+                "\r\n  connect  join a network\r\n  scan     list networks",
+            ),
+            (
+                "help wifi\r",
+                "\r\n  connect  join a network\r\n  scan     list networks",
+            ),
+            ("help\r", "\r\n  wifi   wifi commands\r\n  reset  "),
+            ("help wifi scan\r", "\r\n  scan  list networks"),
+            ("wifi join\r", "error: unknown command 'wifi join'"),
+            ("help wifi join\r", "error: unknown command 'wifi join'"),
+        ];
+
+        for (input, output) in cases {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(input.as_bytes());
+
+            let _ = cli.run(&mut serial);
+
+            let bytes = serial.read_from_write_buffer();
+            let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+            assert_eq!(
+                string,
+                std::format!("{}\r\ncli> {}\r\ncli> ", input.trim_end(), output)
+            );
+        }
+
+        assert!(cli.remove_command(String::from("wifi")).is_ok());
+        assert!(cli.remove_command(String::from("wifi scan")).is_err());
+    }
+
+    #[test]
+    fn test_context_and_closure() {
+        struct Board {
+            led: bool,
+        }
+
+        const LED_ARGS: &[ArgSpec] = &[ArgSpec::required("on", ArgKind::Bool)];
+
+        let mut calls = 0;
+        let mut count = |board: &mut Board,
+                         _: &Args,
+                         writer: Option<&mut dyn core::fmt::Write>|
+         -> CommandCallbackReturn<'static> {
+            calls += 1;
+            if let Some(writer) = writer {
+                write!(writer, "led is {}", board.led)
+                    .map_err(|_| CommandProcessorError::WriteError)?;
+            }
+            Ok(ReturnCode::Success)
+        };
+
+        let mut board = Board { led: false };
+
+        {
+            let mut cli = Cli::<8, 32, Board>::new();
+
+            cli.add_command_with_context(
+                String::from("led"),
+                |board, args, _| {
+                    board.led = args.value("on").unwrap();
+                    Ok(ReturnCode::Success)
+                },
+                Some(LED_ARGS),
+                None,
+            )
+            .unwrap();
+            cli.add_closure(String::from("status"), &mut count, None, None)
+                .unwrap();
+
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(b"led on\r");
+            assert!(cli.run_with_context(&mut serial, &mut board).is_ok());
+            assert!(board.led);
+
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(b"status\r");
+            assert!(cli.run_with_context(&mut serial, &mut board).is_ok());
+
+            let bytes = serial.read_from_write_buffer();
+            let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+            assert_eq!(string, "status\r\ncli> led is true\r\ncli> ");
+        }
+
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("Ax"),
+            |writer| {
+                if let Some(writer) = writer {
+                    write!(writer, "ok").map_err(|_| CommandProcessorError::WriteError)?;
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            None,
+        )
+        .unwrap();
+
+        // Capital A on an empty line, then Delete, Ctrl+Up, Down with no
+        // history and an unknown sequence, none of which reach the line
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"A\x1b[3~\x1b[1;5A\x1b[B\x1b[99zx\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "Ax\r\ncli> ok\r\ncli> ");
+
+        // Up recalls the line from history
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\x1bOA\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "Ax\x1b[K\r\ncli> ok\r\ncli> ");
+
+        // Enter straight after Esc is not lost
+        cli.set_autosuggestions(false);
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"Ax\x1b\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        // In vi mode Esc also moves the cursor back
+        assert!(string.ends_with("\r\ncli> ok\r\ncli> "));
+    }
+
+    #[test]
+    fn test_line_editing() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command_with_args(
+            String::from("say"),
+            |args, writer| {
+                if let Some(writer) = writer {
+                    for arg in args.iter() {
+                        write!(writer, "[{}]", arg)
+                            .map_err(|_| CommandProcessorError::WriteError)?;
+                    }
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            None,
+        )
+        .unwrap();
+
+        // "say ac", Left, insert b, Home, Delete, retype s, End, Backspace
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"say ac\x1b[Db\x1b[H\x1b[3~s\x1b[F\x7f\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "say ac\x08bc\x08\x1b[6Day abc \x1b[7Dsay abc\x1b[6D\x1b[6C\x08 \x08\r\ncli> [ab]\r\ncli> "
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "vi"))]
+    fn test_readline_keys() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command(
+            String::from("on"),
+            |writer| {
+                if let Some(writer) = writer {
+                    write!(writer, "ok").map_err(|_| CommandProcessorError::WriteError)?;
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            None,
+        )
+        .unwrap();
+
+        // Kill "led " from the front with Ctrl+A Alt+F Ctrl+U, clear the
+        // screen with Ctrl+L, then Ctrl+E Ctrl+W Ctrl+Y
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"led on\x01\x1bf\x1bf\x02\x02\x15\x0c\x05\x17\x19\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "led on\x1b[6D\x1b[3C\x1b[3C\x08\x08\x1b[4Don    \x1b[6D\x1b[H\x1b[2Jcli> on\x1b[2D\x1b[2C\x1b[2D  \x1b[2Don\r\ncli> ok\r\ncli> "
+        );
+
+        // Ctrl+Left and Ctrl+Right move by words like Alt+B and Alt+F, here
+        // to the end of "xx" for Ctrl+W to kill it
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"xx on\x1b[1;5D\x1b[1;5D\x1b[1;5C\x17\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "xx on\x1b[2D\x1b[3D\x1b[2C\x1b[2D on  \x1b[5D\r\ncli> ok\r\ncli> "
+        );
+
+        // Ctrl+D deletes forward on a line with text, and logs out on an
+        // empty one
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"x\x02\x04\x04");
+
+        assert!(matches!(cli.run(&mut serial), Err(CliError::EndOfInput)));
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "x\x08 \x08");
+    }
+
+    #[test]
+    #[cfg(not(feature = "vi"))]
+    fn test_keymap() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.keymap_mut()
+            .bind(Key::Ctrl('c'), Action::KillToStart)
+            .unwrap();
+        cli.keymap_mut().unbind(Key::Up).unwrap();
+        cli.keymap_mut()
+            .bind(Key::Function(1), Action::Insert('?'))
+            .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"ab\x03\x1b[A\x1bOP\x1b[B\r");
+
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::UnknownCommand)
+        ));
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.starts_with("ab\x1b[2D  \x1b[2D?\r\ncli> "));
+    }
+
+    #[test]
+    #[cfg(feature = "vi")]
+    fn test_vi_mode() {
+        let mut cli = Cli::<8, 32>::new();
+
+        cli.add_command_with_args(
+            String::from("say"),
+            |args, writer| {
+                if let Some(writer) = writer {
+                    for arg in args.iter() {
+                        write!(writer, "[{}]", arg)
+                            .map_err(|_| CommandProcessorError::WriteError)?;
+                    }
+                }
+
+                Ok(ReturnCode::Success)
+            },
+            None,
+        )
+        .unwrap();
+
+        // Esc then b and cw, then Esc, 0, w and x
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"say xone two\x1bbcwsix\x1b0wx\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.ends_with("\r\ncli> [one][six]\r\ncli> "));
+
+        // The next line starts in insert mode
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"say ix\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.ends_with("\r\ncli> [ix]\r\ncli> "));
+
+        // A control key straight after Esc is handled in normal mode rather
+        // than lost
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"say ls\x1b\x7fx\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.ends_with("\r\ncli> [s]\r\ncli> "));
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"say ls\x1b\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.ends_with("\r\ncli> [ls]\r\ncli> "));
+
+        // Esc and a key clear the suggestion before the cursor moves back,
+        // so that the last character typed stays on screen
+        let mut out = std::string::String::new();
+        cli.feed_slice(b"say l\x1bh", &mut out).1.unwrap();
+        assert_eq!(out, "s\x1b[2may ls\x1b[0m\x1b[5Day l\x1b[K\x08\x08");
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("reset"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("lcd"), callback, None)
+            .unwrap();
+        cli.add_group(String::from("wifi"), None).unwrap();
+        cli.add_command(String::from("wifi scan"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("wifi status"), callback, None)
+            .unwrap();
+
+        // A unique prefix completes with a space after it
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"re\t\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "reset \r\ncli> \r\ncli> ");
+
+        // Subcommands complete up to where they differ, then the second Tab
+        // lists them and redraws the line
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"wi\ts\t\tc\t\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "wifi s\x07\r\nscan    status\r\ncli> wifi scan \r\ncli> \r\ncli> "
+        );
+
+        // No candidates after a command, and the top level includes help
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"led \t\x15l\t\t\x15he\t\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(string.starts_with(
+            "led \x07\x1b[4D    \x1b[4Dl\x07\r\nlcd  led\r\ncli> l\x08 \x08help \r\ncli> "
+        ));
+    }
+
+    #[test]
+    fn test_argument_completion() {
+        const SCHEMA: &[ArgSpec] = &[
+            ArgSpec::required("pin", ArgKind::Str),
+            ArgSpec::required("level", ArgKind::Enum(&["high", "low"])),
+        ];
+
+        let mut cli = Cli::<8, 32>::new();
+        cli.set_autosuggestions(false);
+
+        cli.add_group(String::from("gpio"), None).unwrap();
+        cli.add_command_with_schema(
+            String::from("gpio set"),
+            |_, _| Ok(ReturnCode::Success),
+            SCHEMA,
+            None,
+        )
+        .unwrap();
+        cli.add_completion(String::from("gpio set"), |args, index, completions| {
+            if index == 0 {
+                for pin in ["led1", "led2", "button"] {
+                    completions.push(pin);
+                }
+            }
+            assert!(index < args.len() || completions.prefix().is_empty());
+        })
+        .unwrap();
+
+        assert!(matches!(
+            cli.add_completion(String::from("gpio get"), |_, _, _| ()),
+            Err(CliError::UnknownCommand)
+        ));
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"gpio set b\th\t\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "gpio set button high \r\ncli> \r\ncli> ");
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"gpio set \t\tl\t\r");
+
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::InvalidArgument(_))
+        ));
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert!(
+            string.starts_with("gpio set \x07\r\nbutton  led1    led2\r\ncli> gpio set led\r\n")
+        );
+
+        // Candidates longer than a command name still complete whole
+        let mut cli = Cli::<8, 32, (), 64>::new();
+        cli.set_autosuggestions(false);
+
+        cli.add_command_with_args(String::from("flash"), |_, _| Ok(ReturnCode::Success), None)
+            .unwrap();
+        cli.add_completion(String::from("flash"), |_, _, completions| {
+            completions.push("bootloader-and-application-with-settings");
+        })
+        .unwrap();
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"flash b\t\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "flash bootloader-and-application-with-settings \r\ncli> \r\ncli> "
+        );
+    }
+
+    #[test]
+    fn test_autosuggestions() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("dump"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("dmesg"), callback, None)
+            .unwrap();
+
+        for line in [&b"dump\r"[..], b"dmesg\r"] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
+
+        // Typing through the suggestion, then taking the rest with Right
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"du\x1b[C\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "d\x1b[2mmesg\x1b[0m\x1b[4D\x1b[Ku\x1b[2mmp\x1b[0m\x1b[2D\x1b[Kmp\r\ncli> \r\ncli> "
+        );
+
+        // Typing over it and going back hides it, it is back at the end
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"dm\x1b[D\x1b[C\x1b[C\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "d\x1b[2mump\x1b[0m\x1b[3D\x1b[Km\x1b[2mesg\x1b[0m\x1b[3D\x1b[K\x08m\x1b[2mesg\x1b[0m\x1b[3D\x1b[Kesg\r\ncli> \r\ncli> "
+        );
+    }
+
+    #[test]
+    fn test_reverse_search() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("dump"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        for line in [&b"dump regs\r"[..], b"led on\r", b"dump mem\r"] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
+
+        // Narrowing, stepping to an older match and running it
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\x12dum\x12\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "\r\x1b[K(reverse-i-search)'': \
+             \r\x1b[K(reverse-i-search)'d': dump mem\
+             \r\x1b[K(reverse-i-search)'du': dump mem\
+             \r\x1b[K(reverse-i-search)'dum': dump mem\
+             \r\x1b[K(reverse-i-search)'dum': dump regs\
+             \r\x1b[Kcli> dump regs\r\ncli> \r\ncli> "
+        );
+        assert_eq!(cli.history_buffer.recent().unwrap(), "dump regs");
+
+        // Ctrl+G puts back the line, another key keeps the match to edit
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"le\x12x\x07d\x12on\x1b[H\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test command")),
-        )
-        .unwrap();
+        assert_eq!(
+            string,
+            "le\
+             \r\x1b[K(reverse-i-search)'': \
+             \r\x1b[K(failed reverse-i-search)'x': \
+             \r\x1b[Kcli> le\
+             d\
+             \r\x1b[K(reverse-i-search)'': \
+             \r\x1b[K(reverse-i-search)'o': led on\
+             \r\x1b[K(reverse-i-search)'on': led on\
+             \r\x1b[Kcli> led on\x1b[6D\r\ncli> \r\ncli> "
+        );
 
-        assert!(cli.remove_command(String::from("test")).is_ok());
+        // Esc puts back the line too, and the Enter after it runs that line
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\x12l\x1b\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(
+            string,
+            "\r\x1b[K(reverse-i-search)'': \
+             \r\x1b[K(reverse-i-search)'l': led on\
+             \r\x1b[Kcli> \r\ncli> \r\ncli> "
+        );
+        assert_eq!(cli.history_buffer.recent().unwrap(), "led on");
     }
 
     #[test]
-    fn test_remove_unknown_command() {
+    fn test_run_status() {
         let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
 
-        assert!(cli.remove_command(String::from("test")).is_err());
-    }
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
 
-    #[test]
-    fn test_add_command_too_many() {
-        let mut cli = Cli::<2, 32>::new();
+        let mut serial = serialmock::SerialMock::new();
+        assert!(matches!(cli.run(&mut serial), Ok(RunStatus::Idle)));
 
-        cli.add_command(
-            String::from("test"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "Write this to the serial port") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+        serial.write_to_read_buffer(b"le");
+        assert!(matches!(cli.run(&mut serial), Ok(RunStatus::LineEdited)));
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test command")),
-        )
-        .unwrap();
+        // A read error is reported with the port's error and keeps the line
+        serial.fail_next_read();
+        assert!(matches!(cli.run(&mut serial), Err(CliError::ReadError(()))));
 
-        cli.add_command(
-            String::from("test2"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "test2") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+        serial.write_to_read_buffer(b"d\r");
+        assert!(matches!(
+            cli.run(&mut serial),
+            Ok(RunStatus::CommandExecuted(ReturnCode::Success))
+        ));
+        assert!(matches!(cli.run(&mut serial), Ok(RunStatus::Idle)));
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test2 command")),
-        )
-        .unwrap();
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        assert!(cli
-            .add_command(
-                String::from("test3"),
-                |writer| {
-                    match writer {
-                        Some(writer) => {
-                            match write!(writer, "test3") {
-                                Ok(_) => (),
-                                Err(_) => return Err(CommandProcessorError::WriteError),
-                            };
-                        }
-                        None => (),
-                    };
+        assert_eq!(string, "led\r\ncli> \r\ncli> ");
+    }
 
-                    Ok(ReturnCode::Success)
-                },
-                Some(String::from("test3 command")),
-            )
-            .is_err());
+    #[test]
+    fn test_feed() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        let mut out = std::string::String::new();
+        assert!(matches!(
+            cli.feed_slice(b"", &mut out),
+            (0, Ok(RunStatus::Idle))
+        ));
+        assert_eq!(cli.feed(b'l', &mut out).unwrap(), RunStatus::LineEdited);
+        assert!(matches!(
+            cli.feed_slice(b"e", &mut out),
+            (1, Ok(RunStatus::LineEdited))
+        ));
+        assert!(matches!(
+            cli.feed_slice(b"d\r", &mut out),
+            (2, Ok(RunStatus::CommandExecuted(ReturnCode::Success)))
+        ));
+        assert_eq!(out, "led\r\ncli> \r\ncli> ");
+
+        // Stops at the line that fails, for the rest to be fed after
+        out.clear();
+        let input = b"nope\rled\r";
+        let (fed, result) = cli.feed_slice(input, &mut out);
+        assert_eq!(fed, 5);
+        assert!(matches!(result, Err(CliError::UnknownCommand)));
+
+        let mut bytes = std::vec::Vec::new();
+        assert!(matches!(
+            cli.feed_slice(&input[fed..], &mut ByteSink(|b| bytes.push(b))),
+            (4, Ok(RunStatus::CommandExecuted(ReturnCode::Success)))
+        ));
+        assert_eq!(bytes, b"led\r\ncli> \r\ncli> ");
     }
 
     #[test]
-    fn test_process_command() {
+    fn test_reset() {
         let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        // A session that ends halfway through a line and an escape sequence
+        let mut out = std::string::String::new();
+        cli.feed_slice(b"led\r", &mut out).1.unwrap();
+        cli.feed_slice(b"xx\x1b[", &mut out).1.unwrap();
+
+        cli.reset();
+        out.clear();
+        assert!(matches!(
+            cli.feed_slice(b"led\r\x1b[A", &mut out),
+            (4, Ok(RunStatus::CommandExecuted(ReturnCode::Success)))
+        ));
+        assert_eq!(out, "led\r\ncli> \r\ncli> ");
+
+        // The history is kept
+        out.clear();
+        cli.feed_slice(b"\x1b[A", &mut out).1.unwrap();
+        assert_eq!(out, "led\x1B[K");
+    }
 
-        cli.add_command(
-            String::from("test"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "Write this to the serial port") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+    #[test]
+    fn test_run_split() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |writer| {
+            write!(writer.unwrap(), "on").map_err(|_| CommandProcessorError::WriteError)?;
+            Ok(ReturnCode::Success)
+        };
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test command")),
-        )
-        .unwrap();
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
 
-        cli.add_command(
-            String::from("test2"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "test2") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+        let mut rx = serialmock::SerialMock::new();
+        let mut tx = serialmock::SerialMock::new();
+        cli.init_split(&mut tx).unwrap();
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test2 command")),
-        )
-        .unwrap();
+        rx.write_to_read_buffer(b"led\r");
+        assert!(matches!(
+            cli.run_split(&mut rx, &mut tx),
+            Ok(RunStatus::CommandExecuted(ReturnCode::Success))
+        ));
 
-        let mut serial = serialmock::SerialMock::new();
+        rx.fail_next_read();
+        assert!(matches!(
+            cli.run_split(&mut rx, &mut tx),
+            Err(CliError::ReadError(()))
+        ));
 
-        let test_str = "test\r\ntest2\r\n";
+        let bytes = tx.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        serial.write_to_read_buffer(test_str.as_bytes());
+        assert_eq!(string, "\r\ncli> led\r\ncli> on\r\ncli> ");
+        assert!(rx.read_from_write_buffer().is_empty());
+    }
 
-        assert!(cli.init(&mut serial).is_ok());
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_run_async() {
+        use core::pin::pin;
+        use core::task::Poll;
+        use pipe::{yield_now, Executor, Pipe};
 
-        let result = cli.run(&mut serial);
+        struct Commands {
+            slept: usize,
+        }
 
-        assert!(result.is_ok());
+        impl AsyncCommands for Commands {
+            async fn call<W: embedded_io_async::Write>(
+                &mut self,
+                id: usize,
+                _context: &mut (),
+                args: &Args<'_>,
+                out: &mut W,
+            ) -> CommandCallbackReturn<'static> {
+                assert_eq!((id, args.get(0)), (7, Some("10")));
+                yield_now().await;
+                self.slept += 1;
+                out.write_all(b"awake")
+                    .await
+                    .map_err(|_| CommandProcessorError::WriteError)?;
+                Ok(ReturnCode::Success)
+            }
+        }
 
-        let bytes = serial.read_from_write_buffer();
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |writer| {
+            write!(writer.unwrap(), "on").map_err(|_| CommandProcessorError::WriteError)?;
+            Ok(ReturnCode::Success)
+        };
 
-        // Convert bytes to string
-        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+        let dump: CommandCallback = |writer| {
+            let writer = writer.unwrap();
+            (0..300)
+                .try_for_each(|_| writer.write_char('x'))
+                .map_err(|_| CommandProcessorError::WriteError)?;
+            Ok(ReturnCode::Success)
+        };
 
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.add_command(String::from("dump"), dump, None).unwrap();
+        cli.add_async_command(String::from("sleep"), 7, None, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        let input = Pipe::default();
+        let output = Pipe::default();
+        let (mut rx, mut tx) = (&input, &output);
+        let executor = Executor::default();
+        let mut commands = Commands { slept: 0 };
+
+        {
+            let mut future =
+                pin!(cli.run_async_with::<512, _, _, _>(&mut rx, &mut tx, &mut commands));
+
+            // Waits for input without asking to be polled again
+            assert!(executor.poll(future.as_mut()).is_pending());
+            assert!(!executor.woken());
+
+            input.send(b"led\r");
+            assert!(executor.woken());
+            assert!(matches!(
+                executor.poll(future.as_mut()),
+                Poll::Ready(Ok(ReturnCode::Success))
+            ));
+        }
+        assert_eq!(output.received(), "led\r\ncli> on\r\ncli> ");
+
+        // More output than fits is an error rather than waited out
+        {
+            input.send(b"dump\r");
+            let future = pin!(cli.run_async_with::<64, _, _, _>(&mut rx, &mut tx, &mut commands));
+            assert!(matches!(
+                executor.poll(future),
+                Poll::Ready(Err(CliError::WriteError))
+            ));
+        }
+        assert!(output.received().len() <= "dump".len() + 64);
+
+        // Enough room for all of it
+        {
+            input.send(b"dump\r");
+            let future = pin!(cli.run_async_with::<512, _, _, _>(&mut rx, &mut tx, &mut commands));
+            assert!(matches!(
+                executor.poll(future),
+                Poll::Ready(Ok(ReturnCode::Success))
+            ));
+        }
         assert_eq!(
-            string,
-            "\r\ncli> test\r\ncli> Write this to the serial port\r\ncli> "
+            output.received(),
+            format!("dump\r\ncli> {}\r\ncli> ", "x".repeat(300))
         );
 
-        let bytes = serial.get_buffer();
+        {
+            input.send(b"sleep 10\r");
+            let mut future =
+                pin!(cli.run_async_with::<512, _, _, _>(&mut rx, &mut tx, &mut commands));
+
+            // The command awaits, and runs on when polled again
+            assert!(executor.poll(future.as_mut()).is_pending());
+            assert!(executor.woken());
+            assert!(matches!(
+                executor.poll(future.as_mut()),
+                Poll::Ready(Ok(ReturnCode::Success))
+            ));
+        }
+        assert_eq!(commands.slept, 1);
+        assert_eq!(output.received(), "sleep 10\r\ncli> awake\r\ncli> ");
+        assert_eq!(cli.history_buffer.recent().unwrap(), "sleep 10");
+
+        // Async commands only run from `run_async_with`
+        let mut out = std::string::String::new();
+        assert!(matches!(
+            cli.feed_slice(b"sleep 10\r", &mut out),
+            (9, Err(CliError::AsyncCommand))
+        ));
+
+        input.close();
+        let future = pin!(cli.run_async::<512, _, _>(&mut rx, &mut tx));
+        assert!(matches!(
+            executor.poll(future),
+            Poll::Ready(Err(CliError::EndOfInput))
+        ));
+    }
 
-        // Convert bytes to string
+    #[test]
+    fn test_sizes() {
+        let mut cli = Cli::<8, 32, (), 8, 2, 4>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        assert!(matches!(
+            cli.set_prompt("led> "),
+            Err(CliError::PromptTooLong)
+        ));
+        cli.set_prompt("> ").unwrap();
+        cli.set_autosuggestions(false);
+
+        // Overflowing rings the bell and keeps the line
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"led 12345");
+
+        assert!(matches!(cli.run(&mut serial), Err(CliError::LineTooLong)));
+
+        let bytes = serial.read_from_write_buffer();
         let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        assert_eq!(string, "test\r\ntest2\r\n");
+        assert_eq!(string, "led 1234\x07");
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\r");
+
+        assert!(cli.run(&mut serial).is_ok());
+
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+
+        assert_eq!(string, "\r\n> \r\n> ");
+
+        for line in [&b"led 1\r"[..], b"led 2\r"] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
+        assert!(cli
+            .history_buffer
+            .oldest_ordered()
+            .eq(["led 1", "led 2"].iter()));
     }
 
     #[test]
-    fn test_history() {
+    fn test_history_options() {
         let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+
+        let lines = [
+            &b"led on\r"[..],
+            b"\r",
+            b"led on\r",
+            b"  \r",
+            b" led secret\r",
+            b"led  off\r",
+        ];
+        for &line in &lines {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
+        assert!(cli
+            .history_buffer
+            .oldest_ordered()
+            .eq(["led on", "led  off"].iter()));
+
+        cli.set_history_ignore_empty(false);
+        cli.set_history_ignore_duplicates(false);
+        cli.set_history_ignore_space(false);
+        cli.history_buffer.clear();
+
+        for &line in &lines {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
+        assert!(cli.history_buffer.oldest_ordered().eq([
+            "led on",
+            "",
+            "led on",
+            "  ",
+            " led secret",
+            "led  off"
+        ]
+        .iter()));
+    }
 
-        cli.add_command(
-            String::from("test"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "test") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+    #[test]
+    fn test_history_wraparound() {
+        let mut cli = Cli::<8, 32, (), 32, 2>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        for line in [&b"led a\r"[..], b"led b\r", b"led c\r"] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test command")),
-        )
-        .unwrap();
+        // Up goes back from the most recent line, however the buffer has
+        // wrapped, and stops at the oldest; Down stops at the most recent
+        for (key, line) in [
+            (&b"\x1b[A"[..], "led c"),
+            (b"\x1b[A", "led b"),
+            (b"\x1b[A", "led b"),
+            (b"\x1b[B", "led c"),
+            (b"\x1b[B", "led c"),
+        ] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(key);
+            assert!(cli.run(&mut serial).is_ok());
+            assert_eq!(cli.line.as_str(), line);
+        }
 
+        // Each line starts again from the most recent
         let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"\x1b[A\x1b[A\r\x1b[A");
+        assert!(cli.run(&mut serial).is_ok());
+        assert!(cli.run(&mut serial).is_ok());
+        assert_eq!(cli.line.as_str(), "led b");
+    }
 
-        // test string with ascii up arrow
-        let test_str = "test\r\n\x1B[A\r\n";
+    #[test]
+    fn test_history_expansion() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
 
-        serial.write_to_read_buffer(test_str.as_bytes());
+        cli.add_command(String::from("i2c"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
 
-        assert!(cli.init(&mut serial).is_ok());
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"i2c 0x48\r");
+        assert!(cli.run(&mut serial).is_ok());
 
-        let result = cli.run(&mut serial);
+        // The expanded line is echoed, run and recorded
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"^48^49\r");
 
-        assert!(result.is_ok());
+        assert!(cli.run(&mut serial).is_ok());
 
         let bytes = serial.read_from_write_buffer();
-
-        // Convert bytes to string
         let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        assert_eq!(string, "\r\ncli> test\r\ncli> test\r\ncli> ");
+        assert_eq!(string, "^48^49\r\ni2c 0x49\r\ncli> \r\ncli> ");
+        assert_eq!(cli.history_buffer.recent().unwrap(), "i2c 0x49");
 
-        let bytes = serial.get_buffer();
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"!pwm\r");
 
-        // Convert bytes to string
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::HistoryExpansion)
+        ));
+
+        let bytes = serial.read_from_write_buffer();
         let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        assert_eq!(string, "test\r\n\x1b[A\r\n");
+        assert_eq!(string, "!pwm\r\ncli> error: !pwm: event not found\r\ncli> ");
+        assert_eq!(cli.history_buffer.recent().unwrap(), "i2c 0x49");
+
+        // Turned off, the line runs as typed
+        cli.set_history_expansion(false);
+
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"!!\r");
+
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::UnknownCommand)
+        ));
     }
 
+    #[cfg(feature = "history-storage")]
     #[test]
-    fn test_backspace() {
+    fn test_history_storage() {
+        let mut store = HistoryStore::new(RamFlash::<1024>::new(), 0..1024);
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
         let mut cli = Cli::<8, 32>::new();
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
 
-        cli.add_command(
-            String::from("test"),
-            |writer| {
-                match writer {
-                    Some(writer) => {
-                        match write!(writer, "hello") {
-                            Ok(_) => (),
-                            Err(_) => return Err(CommandProcessorError::WriteError),
-                        };
-                    }
-                    None => (),
-                };
+        let mut out = std::string::String::new();
+        cli.init_with_history(&mut out, &mut store).unwrap();
+        cli.feed_slice(b"led on\r", &mut out).1.unwrap();
+        cli.save_history(&mut store).unwrap();
 
-                Ok(ReturnCode::Success)
-            },
-            Some(String::from("test command")),
-        )
-        .unwrap();
+        // Nothing new to save, so the flash is left alone
+        cli.save_history(&mut store).unwrap();
+        let mut store = HistoryStore::new(store.release(), 0..1024);
 
-        let test_str = "testt\x08\r\n";
+        // After a reset the line is recalled from flash
+        let mut cli = Cli::<8, 32>::new();
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        out.clear();
+        cli.init_with_history(&mut out, &mut store).unwrap();
+        cli.feed_slice(b"\x1b[A", &mut out).1.unwrap();
+        assert_eq!(out, "\r\ncli> led on\x1B[K");
+        assert_eq!(store.release().erases(), 1);
+    }
+
+    #[cfg(feature = "history-command")]
+    #[test]
+    fn test_history_command() {
+        // The built-in takes none of the command slots
+        let mut cli = Cli::<1, 32>::new();
+        let callback: CommandCallback = |_| Ok(ReturnCode::Success);
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        for line in [&b"led on\r"[..], b"led off\r", b"led 50\r"] {
+            let mut serial = serialmock::SerialMock::new();
+            serial.write_to_read_buffer(line);
+            assert!(cli.run(&mut serial).is_ok());
+        }
 
         let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"history 2\r");
 
-        serial.write_to_read_buffer(test_str.as_bytes());
+        assert!(cli.run(&mut serial).is_ok());
 
-        assert!(cli.init(&mut serial).is_ok());
+        let bytes = serial.read_from_write_buffer();
+        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
 
-        let result = cli.run(&mut serial);
+        assert_eq!(
+            string,
+            "history 2\r\ncli> \r\n   2  led off\r\n   3  led 50\r\ncli> "
+        );
 
-        assert!(result.is_ok());
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"history x\r");
 
-        let bytes = serial.read_from_write_buffer();
+        assert!(matches!(
+            cli.run(&mut serial),
+            Err(CliError::InvalidArgument(_))
+        ));
 
-        // Convert bytes to string
-        let string = std::string::String::from_utf8(bytes.to_vec()).unwrap();
+        let mut serial = serialmock::SerialMock::new();
+        serial.write_to_read_buffer(b"history -c\r");
 
-        assert_eq!(string, "\r\ncli> testt\x08 \x08\r\ncli> hello\r\ncli> ");
+        assert!(cli.run(&mut serial).is_ok());
+        assert!(cli
+            .history_buffer
+            .oldest_ordered()
+            .eq(["history -c"].iter()));
     }
 }
@@ -0,0 +1,484 @@
+use core::fmt::{self, Write};
+
+use heapless::{String, Vec};
+
+/// Number of killed pieces of text kept for yanking
+pub(crate) const KILL_RING_SIZE: usize = 4;
+
+/// Where a cursor movement goes, or how far a kill reaches. Words are
+/// separated by spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Motion {
+    Left,
+    Right,
+    Home,
+    End,
+    /// Start of the word before the cursor
+    WordStart,
+    /// End of the word after the cursor
+    WordEnd,
+    /// Start of the next word
+    #[cfg(feature = "vi")]
+    NextWord,
+}
+
+/// The line being edited and the cursor position within it.
+///
+/// Every edit writes what is needed to bring the terminal up to date: only
+/// the text from the cursor onwards is redrawn, and the cursor is moved
+/// with backspace or CSI `n D`/`n C` sequences. The line only ever holds
+/// printable ASCII, so byte offsets and columns are the same.
+///
+/// Text removed by the kill commands goes to a small kill ring, from which
+/// it can be yanked back.
+pub(crate) struct LineBuffer<const N: usize> {
+    buffer: String<N>,
+    cursor: usize,
+    /// Oldest entry first
+    kill_ring: Vec<String<N>, KILL_RING_SIZE>,
+    /// Start of the text inserted by the last yank and the kill ring entry
+    /// it came from, kept until another key is handled
+    yank: Option<(usize, usize)>,
+    /// Text was refused because the line had no room for it
+    overflow: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub(crate) const fn new() -> LineBuffer<N> {
+        LineBuffer {
+            buffer: String::new(),
+            cursor: 0,
+            kill_ring: Vec::new(),
+            yank: None,
+            overflow: false,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.buffer
+    }
+
+    pub(crate) fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub(crate) fn at_end(&self) -> bool {
+        self.cursor == self.buffer.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+    }
+
+    /// Inserts a character at the cursor, returning `false` if the line is
+    /// full. Characters other than printable ASCII are ignored.
+    pub(crate) fn insert(&mut self, c: char, out: &mut dyn Write) -> Result<bool, fmt::Error> {
+        if !(c.is_ascii_graphic() || c == ' ') {
+            return Ok(true);
+        }
+
+        self.insert_str(c.encode_utf8(&mut [0; 4]), out)
+    }
+
+    /// Inserts text at the cursor. Text that does not all fit on the line
+    /// is not inserted at all, `false` is returned and the overflow is kept
+    /// for [`take_overflow`](Self::take_overflow).
+    pub(crate) fn insert_str(
+        &mut self,
+        text: &str,
+        out: &mut dyn Write,
+    ) -> Result<bool, fmt::Error> {
+        if text.len() > N - self.buffer.len() {
+            self.overflow = true;
+            return Ok(false);
+        }
+        if text.is_empty() {
+            return Ok(true);
+        }
+
+        let tail = self.copy_from(self.cursor);
+        self.buffer.truncate(self.cursor);
+        // Cannot fail, the line has room for the text
+        let _ = self.buffer.push_str(text);
+        let _ = self.buffer.push_str(&tail);
+
+        out.write_str(&self.buffer[self.cursor..])?;
+        self.cursor += text.len();
+        cursor_left(out, tail.len())?;
+
+        Ok(true)
+    }
+
+    /// Whether text was refused since the last call
+    pub(crate) fn take_overflow(&mut self) -> bool {
+        core::mem::replace(&mut self.overflow, false)
+    }
+
+    /// Removes the character before the cursor
+    pub(crate) fn backspace(&mut self, out: &mut dyn Write) -> fmt::Result {
+        if self.cursor == 0 {
+            return Ok(());
+        }
+
+        self.remove(self.cursor - 1, self.cursor, out)?;
+        Ok(())
+    }
+
+    /// Removes the character under the cursor
+    pub(crate) fn delete(&mut self, out: &mut dyn Write) -> fmt::Result {
+        if self.cursor == self.buffer.len() {
+            return Ok(());
+        }
+
+        self.remove(self.cursor, self.cursor + 1, out)?;
+        Ok(())
+    }
+
+    /// Where `motion` would take the cursor
+    pub(crate) fn target(&self, motion: Motion) -> usize {
+        match motion {
+            Motion::Left => self.cursor.saturating_sub(1),
+            Motion::Right => (self.cursor + 1).min(self.buffer.len()),
+            Motion::Home => 0,
+            Motion::End => self.buffer.len(),
+            Motion::WordStart => {
+                let before = self.buffer[..self.cursor].trim_end_matches(' ');
+                before.rfind(' ').map_or(0, |space| space + 1)
+            }
+            Motion::WordEnd => {
+                let after = &self.buffer[self.cursor..];
+                let word = after.trim_start_matches(' ');
+                let spaces = after.len() - word.len();
+                self.cursor + spaces + word.find(' ').unwrap_or(word.len())
+            }
+            #[cfg(feature = "vi")]
+            Motion::NextWord => {
+                let after = &self.buffer[self.cursor..];
+                let word = after.find(' ').unwrap_or(after.len());
+                let spaces = after[word..].len() - after[word..].trim_start_matches(' ').len();
+                self.cursor + word + spaces
+            }
+        }
+    }
+
+    pub(crate) fn move_by(&mut self, motion: Motion, out: &mut dyn Write) -> fmt::Result {
+        let position = self.target(motion);
+
+        if position < self.cursor {
+            cursor_left(out, self.cursor - position)?;
+        } else if position == self.cursor + 1 {
+            // Writing the character under the cursor is shorter than CSI C
+            out.write_str(&self.buffer[self.cursor..position])?;
+        } else {
+            cursor_right(out, position - self.cursor)?;
+        }
+        self.cursor = position;
+
+        Ok(())
+    }
+
+    /// Kills the text between the cursor and where `motion` would take it
+    pub(crate) fn kill_by(&mut self, motion: Motion, out: &mut dyn Write) -> fmt::Result {
+        let target = self.target(motion);
+        self.kill(target.min(self.cursor), target.max(self.cursor), out)
+    }
+
+    /// Inserts the most recently killed text at the cursor
+    pub(crate) fn yank(&mut self, out: &mut dyn Write) -> fmt::Result {
+        self.yank = None;
+
+        if let Some(last) = self.kill_ring.len().checked_sub(1) {
+            self.yank_entry(last, out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Straight after a yank, replaces the yanked text with the next older
+    /// entry of the kill ring
+    pub(crate) fn yank_pop(&mut self, out: &mut dyn Write) -> fmt::Result {
+        if let Some((start, entry)) = self.yank.take() {
+            self.remove(start, self.cursor, out)?;
+
+            let older = entry.checked_sub(1).unwrap_or(self.kill_ring.len() - 1);
+            self.yank_entry(older, out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends a yank, so that a following [`yank_pop`](Self::yank_pop) does
+    /// nothing. Called for every key other than yank and yank-pop.
+    pub(crate) fn end_yank(&mut self) {
+        self.yank = None;
+    }
+
+    /// Writes the line after the prompt has been written, leaving the
+    /// terminal's cursor where the line's cursor is
+    pub(crate) fn redraw(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str(&self.buffer)?;
+        cursor_left(out, self.buffer.len() - self.cursor)
+    }
+
+    /// Replaces the whole line, leaving the cursor at the end
+    pub(crate) fn replace(&mut self, text: &str, out: &mut dyn Write) -> fmt::Result {
+        cursor_left(out, self.cursor)?;
+        self.set(text);
+
+        out.write_str(&self.buffer)?;
+        // Clear whatever is left of a longer previous line
+        out.write_str("\x1B[K")
+    }
+
+    /// Replaces the whole line without writing anything, leaving the cursor
+    /// at the end
+    pub(crate) fn set(&mut self, text: &str) {
+        self.buffer.clear();
+        for c in text.chars() {
+            if self.buffer.push(c).is_err() {
+                break;
+            }
+        }
+        self.cursor = self.buffer.len();
+    }
+
+    fn yank_entry(&mut self, entry: usize, out: &mut dyn Write) -> fmt::Result {
+        let start = self.cursor;
+        let text = self.kill_ring[entry].clone();
+        if self.insert_str(&text, out)? {
+            self.yank = Some((start, entry));
+        }
+
+        Ok(())
+    }
+
+    /// Removes `start..end` and puts it in the kill ring
+    fn kill(&mut self, start: usize, end: usize, out: &mut dyn Write) -> fmt::Result {
+        if start == end {
+            return Ok(());
+        }
+
+        let killed = self.remove(start, end, out)?;
+        if self.kill_ring.is_full() {
+            self.kill_ring.remove(0);
+        }
+        let _ = self.kill_ring.push(killed);
+
+        Ok(())
+    }
+
+    /// Removes `start..end`, which must contain the cursor, and redraws the
+    /// rest of the line over it. The cursor ends up at `start`.
+    fn remove(
+        &mut self,
+        start: usize,
+        end: usize,
+        out: &mut dyn Write,
+    ) -> Result<String<N>, fmt::Error> {
+        let mut removed = String::new();
+        // Cannot fail, the range is never longer than the line
+        let _ = removed.push_str(&self.buffer[start..end]);
+
+        let tail = self.copy_from(end);
+        self.buffer.truncate(start);
+        let _ = self.buffer.push_str(&tail);
+
+        cursor_left(out, self.cursor - start)?;
+        self.cursor = start;
+
+        out.write_str(&tail)?;
+        for _ in 0..removed.len() {
+            out.write_char(' ')?;
+        }
+        cursor_left(out, tail.len() + removed.len())?;
+
+        Ok(removed)
+    }
+
+    fn copy_from(&self, start: usize) -> String<N> {
+        let mut tail = String::new();
+        // Cannot fail, the tail is never longer than the line
+        let _ = tail.push_str(&self.buffer[start..]);
+        tail
+    }
+}
+
+pub(crate) fn cursor_left(out: &mut dyn Write, columns: usize) -> fmt::Result {
+    match columns {
+        0 => Ok(()),
+        1 => out.write_char('\x08'),
+        _ => write!(out, "\x1B[{}D", columns),
+    }
+}
+
+fn cursor_right(out: &mut dyn Write, columns: usize) -> fmt::Result {
+    match columns {
+        0 => Ok(()),
+        _ => write!(out, "\x1B[{}C", columns),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_str(line: &mut LineBuffer<16>, text: &str) -> std::string::String {
+        let mut out = std::string::String::new();
+        for c in text.chars() {
+            line.insert(c, &mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut line = LineBuffer::<16>::new();
+
+        assert_eq!(type_str(&mut line, "led on"), "led on");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::Home, &mut out).unwrap();
+        line.move_by(Motion::Right, &mut out).unwrap();
+        assert_eq!(out, "\x1b[6Dl");
+
+        assert_eq!(type_str(&mut line, "x"), "xed on\x1b[5D");
+        assert_eq!(line.as_str(), "lxed on");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::End, &mut out).unwrap();
+        line.move_by(Motion::Left, &mut out).unwrap();
+        assert_eq!(out, "\x1b[5C\x08");
+        assert_eq!(type_str(&mut line, "X"), "Xn\x08");
+        assert_eq!(line.as_str(), "lxed oXn");
+    }
+
+    #[test]
+    fn test_insert_full() {
+        let mut line = LineBuffer::<16>::new();
+
+        assert_eq!(
+            type_str(&mut line, "0123456789abcdefgh"),
+            "0123456789abcdef"
+        );
+        assert_eq!(line.as_str(), "0123456789abcdef");
+        assert!(line.take_overflow());
+        assert!(!line.take_overflow());
+
+        // Text is not cut to fit
+        let mut line = LineBuffer::<16>::new();
+        type_str(&mut line, "0123456789abcd");
+        let mut out = std::string::String::new();
+        assert!(!line.insert_str("efg", &mut out).unwrap());
+        assert_eq!(out, "");
+        assert_eq!(line.as_str(), "0123456789abcd");
+        assert!(line.take_overflow());
+    }
+
+    #[test]
+    fn test_backspace_and_delete() {
+        let mut line = LineBuffer::<16>::new();
+        type_str(&mut line, "abcd");
+
+        let mut out = std::string::String::new();
+        line.backspace(&mut out).unwrap();
+        assert_eq!(out, "\x08 \x08");
+        assert_eq!(line.as_str(), "abc");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::Left, &mut out).unwrap();
+        line.move_by(Motion::Left, &mut out).unwrap();
+        line.backspace(&mut out).unwrap();
+        assert_eq!(out, "\x08\x08\x08bc \x1b[3D");
+        assert_eq!(line.as_str(), "bc");
+
+        let mut out = std::string::String::new();
+        line.delete(&mut out).unwrap();
+        assert_eq!(out, "c \x1b[2D");
+        assert_eq!(line.as_str(), "c");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::End, &mut out).unwrap();
+        line.delete(&mut out).unwrap();
+        assert_eq!(out, "c");
+        assert_eq!(line.as_str(), "c");
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut line = LineBuffer::<16>::new();
+        type_str(&mut line, "a long line");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::Left, &mut out).unwrap();
+        line.replace("short", &mut out).unwrap();
+        assert_eq!(out, "\x08\x1b[10Dshort\x1b[K");
+        assert_eq!(line.as_str(), "short");
+    }
+
+    #[test]
+    fn test_words() {
+        let mut line = LineBuffer::<16>::new();
+        type_str(&mut line, "wifi  join home");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::WordStart, &mut out).unwrap();
+        line.move_by(Motion::WordStart, &mut out).unwrap();
+        line.move_by(Motion::WordStart, &mut out).unwrap();
+        line.move_by(Motion::WordStart, &mut out).unwrap();
+        assert_eq!(out, "\x1b[4D\x1b[5D\x1b[6D");
+
+        let mut out = std::string::String::new();
+        line.move_by(Motion::WordEnd, &mut out).unwrap();
+        line.move_by(Motion::WordEnd, &mut out).unwrap();
+        assert_eq!(out, "\x1b[4C\x1b[6C");
+
+        let mut out = std::string::String::new();
+        line.kill_by(Motion::WordStart, &mut out).unwrap();
+        assert_eq!(out, "\x1b[4D home    \x1b[9D");
+        assert_eq!(line.as_str(), "wifi   home");
+    }
+
+    #[test]
+    fn test_kill_and_yank() {
+        let mut line = LineBuffer::<16>::new();
+        type_str(&mut line, "led 3 on");
+        let mut out = std::string::String::new();
+
+        line.kill_by(Motion::WordStart, &mut out).unwrap();
+        line.move_by(Motion::WordStart, &mut out).unwrap();
+        line.kill_by(Motion::End, &mut out).unwrap();
+        line.move_by(Motion::Home, &mut out).unwrap();
+        line.kill_by(Motion::End, &mut out).unwrap();
+        assert_eq!(line.as_str(), "");
+
+        // Most recent kill first, then older ones
+        let mut out = std::string::String::new();
+        line.yank(&mut out).unwrap();
+        assert_eq!(out, "led ");
+        line.yank_pop(&mut out).unwrap();
+        assert_eq!(line.as_str(), "3 ");
+        line.yank_pop(&mut out).unwrap();
+        assert_eq!(line.as_str(), "on");
+        line.yank_pop(&mut out).unwrap();
+        assert_eq!(line.as_str(), "led ");
+
+        // Only straight after a yank
+        line.end_yank();
+        line.yank_pop(&mut out).unwrap();
+        assert_eq!(line.as_str(), "led ");
+
+        line.yank(&mut out).unwrap();
+        line.move_by(Motion::Left, &mut out).unwrap();
+        line.kill_by(Motion::Home, &mut out).unwrap();
+        assert_eq!(line.as_str(), " ");
+
+        let mut out = std::string::String::new();
+        line.redraw(&mut out).unwrap();
+        assert_eq!(out, " \x08");
+    }
+}
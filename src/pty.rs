@@ -0,0 +1,159 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+
+/// Puts a terminal into raw mode while it lives, so that keys reach the
+/// `Cli` as they are pressed rather than a line at a time, with nothing
+/// echoed by the terminal itself. The terminal is put back when dropped.
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    /// Fails with the OS error, `ENOTTY` if `fd` is not a terminal
+    pub fn enable(fd: RawFd) -> io::Result<RawMode> {
+        let saved = make_raw(fd)?;
+        Ok(RawMode { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: `saved` is a termios read from the same terminal
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// A pseudo-terminal in raw mode, for a terminal emulator to connect to
+/// while the `Cli` runs over it, e.g. `screen /dev/pts/3`
+pub struct Pty {
+    master: File,
+    /// Kept open so that reading the master waits for input rather than
+    /// failing while no terminal is connected
+    _slave: File,
+    name: String,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Pty> {
+        // SAFETY: the name is copied out of the buffer it is written to
+        // before the buffer goes
+        let (master, name) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            check(fd)?;
+            let master = File::from_raw_fd(fd);
+
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+
+            let mut name = [0; 64];
+            match libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) {
+                0 => (),
+                error => return Err(io::Error::from_raw_os_error(error)),
+            }
+            let name = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+
+            (master, name)
+        };
+
+        let slave = OpenOptions::new().read(true).write(true).open(&name)?;
+        // The line discipline is shared, so this also holds for whoever
+        // connects to the slave
+        make_raw(master.as_raw_fd())?;
+
+        Ok(Pty {
+            master,
+            _slave: slave,
+            name,
+        })
+    }
+
+    /// The path of the terminal to connect to
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Read for &Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.master).read(buf)
+    }
+}
+
+impl Write for &Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.master).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.master).flush()
+    }
+}
+
+/// Puts the terminal `fd` into raw mode, returning its settings from before
+fn make_raw(fd: RawFd) -> io::Result<libc::termios> {
+    let mut termios = MaybeUninit::uninit();
+
+    // SAFETY: `termios` is only read once `tcgetattr` has filled it in
+    unsafe {
+        check(libc::tcgetattr(fd, termios.as_mut_ptr()))?;
+        let saved = termios.assume_init();
+
+        let mut raw = saved;
+        libc::cfmakeraw(&mut raw);
+        check(libc::tcsetattr(fd, libc::TCSANOW, &raw))?;
+
+        Ok(saved)
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cli, CommandCallback, ReturnCode};
+    use heapless::String;
+
+    #[test]
+    fn test_pty() {
+        let mut cli = Cli::<8, 32>::new();
+        let callback: CommandCallback = |writer| {
+            write!(writer.unwrap(), "on").unwrap();
+            Ok(ReturnCode::Success)
+        };
+
+        cli.add_command(String::from("led"), callback, None)
+            .unwrap();
+        cli.set_autosuggestions(false);
+
+        let pty = Pty::open().unwrap();
+        let mut terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty.name())
+            .unwrap();
+
+        // Not a terminal
+        let null = File::open("/dev/null").unwrap();
+        assert!(RawMode::enable(null.as_raw_fd()).is_err());
+
+        // Raw, so the line is not held back until Enter nor turned into
+        // `\n`, and Ctrl+D reaches the `Cli` to end the session
+        terminal.write_all(b"led\r\x04").unwrap();
+        cli.run_std(&pty, &pty).unwrap();
+
+        let expected = "\r\ncli> led\r\ncli> on\r\ncli> ";
+        let mut output = vec![0; expected.len()];
+        terminal.read_exact(&mut output).unwrap();
+        assert_eq!(std::string::String::from_utf8(output).unwrap(), expected);
+    }
+}
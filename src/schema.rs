@@ -0,0 +1,724 @@
+use core::fmt::{self, Write};
+
+use heapless::{String, Vec};
+
+use crate::args::{Args, MAX_TOKENS};
+use crate::complete::Completions;
+
+/// The type of value an argument accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind<'s> {
+    /// Integer within an inclusive range, written in decimal or with a
+    /// `0x`/`0b` prefix
+    Int {
+        min: i64,
+        max: i64,
+    },
+    Float,
+    /// `true`/`false`, `on`/`off`, `yes`/`no` or `1`/`0`
+    Bool,
+    /// One of a fixed set of words
+    Enum(&'s [&'s str]),
+    Str,
+}
+
+impl ArgKind<'static> {
+    pub const U8: ArgKind<'static> = ArgKind::int(0, u8::MAX as i64);
+    pub const U16: ArgKind<'static> = ArgKind::int(0, u16::MAX as i64);
+    pub const U32: ArgKind<'static> = ArgKind::int(0, u32::MAX as i64);
+    pub const I8: ArgKind<'static> = ArgKind::int(i8::MIN as i64, i8::MAX as i64);
+    pub const I16: ArgKind<'static> = ArgKind::int(i16::MIN as i64, i16::MAX as i64);
+    pub const I32: ArgKind<'static> = ArgKind::int(i32::MIN as i64, i32::MAX as i64);
+}
+
+impl<'s> ArgKind<'s> {
+    pub const fn int(min: i64, max: i64) -> ArgKind<'s> {
+        ArgKind::Int { min, max }
+    }
+
+    fn accepts(&self, arg: &str) -> bool {
+        match *self {
+            ArgKind::Int { min, max } => {
+                matches!(parse_int(arg), Some(value) if min <= value && value <= max)
+            }
+            ArgKind::Float => f32::from_arg(arg).is_some(),
+            ArgKind::Bool => bool::from_arg(arg).is_some(),
+            ArgKind::Enum(words) => words.contains(&arg),
+            ArgKind::Str => true,
+        }
+    }
+}
+
+impl fmt::Display for ArgKind<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgKind::Int { min, max } => write!(f, "integer {}..={}", min, max),
+            ArgKind::Float => write!(f, "number"),
+            ArgKind::Bool => write!(f, "boolean"),
+            ArgKind::Enum(words) => {
+                write!(f, "one of ")?;
+                for (i, word) in words.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", word)?;
+                }
+                Ok(())
+            }
+            ArgKind::Str => write!(f, "string"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArgStyle {
+    Positional,
+    /// `-v` or `--verbose`
+    Flag,
+    /// `-n 5`, `-n5`, `--count 5` or `--count=5`
+    Option,
+}
+
+/// Declares one argument, flag or option a command expects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgSpec<'s> {
+    pub(crate) name: &'s str,
+    pub(crate) short: Option<char>,
+    pub(crate) kind: ArgKind<'s>,
+    pub(crate) required: bool,
+    pub(crate) style: ArgStyle,
+}
+
+impl<'s> ArgSpec<'s> {
+    pub const fn required(name: &'s str, kind: ArgKind<'s>) -> ArgSpec<'s> {
+        ArgSpec {
+            name,
+            short: None,
+            kind,
+            required: true,
+            style: ArgStyle::Positional,
+        }
+    }
+
+    /// An argument that may be left off the end of the line
+    pub const fn optional(name: &'s str, kind: ArgKind<'s>) -> ArgSpec<'s> {
+        ArgSpec {
+            name,
+            short: None,
+            kind,
+            required: false,
+            style: ArgStyle::Positional,
+        }
+    }
+
+    /// A flag given as `-short` or `--long`
+    pub const fn flag(short: Option<char>, long: &'s str) -> ArgSpec<'s> {
+        ArgSpec {
+            name: long,
+            short,
+            kind: ArgKind::Bool,
+            required: false,
+            style: ArgStyle::Flag,
+        }
+    }
+
+    /// An option given as `-short value` or `--long=value`
+    pub const fn option(short: Option<char>, long: &'s str, kind: ArgKind<'s>) -> ArgSpec<'s> {
+        ArgSpec {
+            name: long,
+            short,
+            kind,
+            required: false,
+            style: ArgStyle::Option,
+        }
+    }
+
+    pub(crate) fn is_positional(&self) -> bool {
+        self.style == ArgStyle::Positional
+    }
+}
+
+/// How a flag or option is looked up: by its short `char` or long name
+pub trait OptionName {
+    fn matches(&self, spec: &ArgSpec<'_>) -> bool;
+}
+
+impl OptionName for char {
+    fn matches(&self, spec: &ArgSpec<'_>) -> bool {
+        !spec.is_positional() && spec.short == Some(*self)
+    }
+}
+
+impl OptionName for &str {
+    fn matches(&self, spec: &ArgSpec<'_>) -> bool {
+        !spec.is_positional() && spec.name == *self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgErrorReason {
+    Missing,
+    Unexpected,
+    Invalid,
+    UnknownOption,
+    MissingValue,
+}
+
+/// An argument that did not match the command's schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgError {
+    /// Position on the line after the command name, counting from 1
+    pub index: usize,
+    pub reason: ArgErrorReason,
+}
+
+/// The arguments of a line sorted into positionals and options.
+///
+/// Values are kept as ranges into the tokenized line, the same as
+/// [`Args`] does for whole tokens.
+pub(crate) struct Matches {
+    pub(crate) positional: Vec<(usize, usize), MAX_TOKENS>,
+    pub(crate) options: Vec<(usize, Option<(usize, usize)>), MAX_TOKENS>,
+}
+
+/// Sorts the arguments into positionals, flags and options, checking
+/// every one against the schema before the callback runs
+pub(crate) fn match_args<'s>(
+    schema: &'s [ArgSpec<'s>],
+    args: &Args<'s>,
+) -> Result<Matches, ArgErrorMessage<'s>> {
+    let mut matches = Matches {
+        positional: Vec::new(),
+        options: Vec::new(),
+    };
+    let mut positional = schema.iter().filter(|spec| spec.is_positional());
+    let mut options_done = false;
+    let mut i = 0;
+
+    while let Some(arg) = args.get(i) {
+        let (start, end) = args.range(i);
+        let index = i + 1;
+        let error = move |reason, spec| ArgErrorMessage {
+            error: ArgError { index, reason },
+            spec,
+            arg: Some(arg),
+        };
+        i += 1;
+
+        if options_done || arg == "-" || !arg.starts_with('-') || is_number(arg) {
+            let spec = positional
+                .next()
+                .ok_or_else(|| error(ArgErrorReason::Unexpected, None))?;
+
+            if !spec.kind.accepts(arg) {
+                return Err(error(ArgErrorReason::Invalid, Some(spec)));
+            }
+
+            matches
+                .positional
+                .push((start, end))
+                .map_err(|_| error(ArgErrorReason::Unexpected, None))?;
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some((end - value.len(), end))),
+                None => (long, None),
+            };
+
+            let (position, spec) = find_option(schema, name)
+                .ok_or_else(|| error(ArgErrorReason::UnknownOption, None))?;
+
+            let value = match (spec.style, value) {
+                (ArgStyle::Flag, None) => None,
+                (ArgStyle::Flag, Some(_)) => return Err(error(ArgErrorReason::Invalid, None)),
+                (_, Some(value)) => Some(value),
+                (_, None) => Some(
+                    next_value(args, &mut i)
+                        .ok_or_else(|| error(ArgErrorReason::MissingValue, Some(spec)))?,
+                ),
+            };
+
+            matches
+                .push_option(args, position, spec, value)
+                .map_err(|reason| error(reason, Some(spec)))?;
+        } else {
+            for (offset, short) in arg.char_indices().skip(1) {
+                let (position, spec) = find_option(schema, short)
+                    .ok_or_else(|| error(ArgErrorReason::UnknownOption, None))?;
+
+                if spec.style == ArgStyle::Flag {
+                    matches
+                        .push_option(args, position, spec, None)
+                        .map_err(|reason| error(reason, Some(spec)))?;
+                    continue;
+                }
+
+                // The rest of the token is the value, otherwise the next one
+                let value_start = start + offset + short.len_utf8();
+                let value = if value_start < end {
+                    (value_start, end)
+                } else {
+                    next_value(args, &mut i)
+                        .ok_or_else(|| error(ArgErrorReason::MissingValue, Some(spec)))?
+                };
+
+                matches
+                    .push_option(args, position, spec, Some(value))
+                    .map_err(|reason| error(reason, Some(spec)))?;
+                break;
+            }
+        }
+    }
+
+    if let Some(spec) = positional.find(|spec| spec.required) {
+        return Err(ArgErrorMessage {
+            error: ArgError {
+                index: args.len() + 1,
+                reason: ArgErrorReason::Missing,
+            },
+            spec: Some(spec),
+            arg: None,
+        });
+    }
+
+    Ok(matches)
+}
+
+impl Matches {
+    fn push_option(
+        &mut self,
+        args: &Args<'_>,
+        position: usize,
+        spec: &ArgSpec<'_>,
+        value: Option<(usize, usize)>,
+    ) -> Result<(), ArgErrorReason> {
+        if let Some(value) = value {
+            if !spec.kind.accepts(args.slice(value)) {
+                return Err(ArgErrorReason::Invalid);
+            }
+        }
+
+        self.options
+            .push((position, value))
+            .map_err(|_| ArgErrorReason::Unexpected)
+    }
+}
+
+fn next_value(args: &Args<'_>, i: &mut usize) -> Option<(usize, usize)> {
+    if *i < args.len() {
+        *i += 1;
+        Some(args.range(*i - 1))
+    } else {
+        None
+    }
+}
+
+fn find_option<'s>(
+    schema: &'s [ArgSpec<'s>],
+    name: impl OptionName,
+) -> Option<(usize, &'s ArgSpec<'s>)> {
+    schema
+        .iter()
+        .enumerate()
+        .find(|(_, spec)| name.matches(spec))
+}
+
+/// Offers what the argument at `index` can be: the long names of flags and
+/// options for a word starting with `--`, or the words of an
+/// [`ArgKind::Enum`] positional or option value
+pub(crate) fn complete_arg(
+    schema: &[ArgSpec<'_>],
+    args: &Args<'_>,
+    index: usize,
+    completions: &mut Completions<'_>,
+) {
+    let current = args.get(index).unwrap_or("");
+
+    if current.starts_with("--") {
+        for spec in schema.iter().filter(|spec| !spec.is_positional()) {
+            let mut name: String<34> = String::new();
+            if write!(name, "--{}", spec.name).is_ok() {
+                completions.push(&name);
+            }
+        }
+        return;
+    }
+
+    // Walk the arguments before the current one as `match_args` does
+    let mut positional = schema.iter().filter(|spec| spec.is_positional());
+    let mut value_for = None;
+    let mut options_done = false;
+
+    for arg in args.iter().take(index) {
+        if value_for.take().is_some() {
+            continue;
+        }
+
+        if options_done || arg == "-" || !arg.starts_with('-') || is_number(arg) {
+            positional.next();
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            value_for = find_option(schema, long)
+                .filter(|(_, spec)| spec.style == ArgStyle::Option)
+                .map(|(_, spec)| spec);
+        } else {
+            // Only an option at the end of a group of short ones, with no
+            // value attached, takes the next argument
+            for (offset, short) in arg.char_indices().skip(1) {
+                match find_option(schema, short) {
+                    Some((_, spec)) if spec.style == ArgStyle::Option => {
+                        if offset + short.len_utf8() == arg.len() {
+                            value_for = Some(spec);
+                        }
+                        break;
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    let spec = match value_for {
+        Some(spec) => Some(spec),
+        None if options_done || !current.starts_with('-') => positional.next(),
+        None => None,
+    };
+
+    if let Some(ArgSpec {
+        kind: ArgKind::Enum(words),
+        ..
+    }) = spec
+    {
+        for word in words.iter() {
+            completions.push(word);
+        }
+    }
+}
+
+/// Negative numbers are positionals rather than short options
+fn is_number(arg: &str) -> bool {
+    arg.as_bytes().get(1).is_some_and(u8::is_ascii_digit)
+}
+
+/// Formats an [`ArgError`] for the user, e.g.
+/// `arg 2 'freq': expected integer 1..=1000`
+pub(crate) struct ArgErrorMessage<'s> {
+    pub(crate) error: ArgError,
+    pub(crate) spec: Option<&'s ArgSpec<'s>>,
+    pub(crate) arg: Option<&'s str>,
+}
+
+impl fmt::Display for ArgErrorMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "arg {}", self.error.index)?;
+
+        if let Some(spec) = self.spec {
+            match spec.style {
+                ArgStyle::Positional => write!(f, " '{}'", spec.name)?,
+                _ => write!(f, " '--{}'", spec.name)?,
+            }
+        }
+
+        match (self.error.reason, self.spec) {
+            (ArgErrorReason::Invalid, Some(spec)) => write!(f, ": expected {}", spec.kind),
+            (ArgErrorReason::Invalid, None) => {
+                write!(f, ": unexpected value '{}'", self.arg.unwrap_or(""))
+            }
+            (ArgErrorReason::Missing, _) => write!(f, ": missing"),
+            (ArgErrorReason::MissingValue, _) => write!(f, ": missing value"),
+            (ArgErrorReason::UnknownOption, _) => {
+                write!(f, ": unknown option '{}'", self.arg.unwrap_or(""))
+            }
+            (ArgErrorReason::Unexpected, _) => {
+                write!(f, ": unexpected '{}'", self.arg.unwrap_or(""))
+            }
+        }
+    }
+}
+
+/// Conversion from an argument token into a typed value
+pub trait FromArg<'l>: Sized {
+    fn from_arg(arg: &'l str) -> Option<Self>;
+}
+
+impl<'l> FromArg<'l> for &'l str {
+    fn from_arg(arg: &'l str) -> Option<Self> {
+        Some(arg)
+    }
+}
+
+impl FromArg<'_> for bool {
+    fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "true" | "on" | "yes" | "1" => Some(true),
+            "false" | "off" | "no" | "0" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl FromArg<'_> for f32 {
+    fn from_arg(arg: &str) -> Option<Self> {
+        arg.parse().ok()
+    }
+}
+
+macro_rules! impl_from_arg_int {
+    ($($int:ty),*) => {
+        $(
+            impl FromArg<'_> for $int {
+                fn from_arg(arg: &str) -> Option<Self> {
+                    parse_int(arg).and_then(|value| <$int>::try_from(value).ok())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_arg_int!(u8, u16, u32, i8, i16, i32, i64);
+
+fn parse_int(arg: &str) -> Option<i64> {
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg),
+    };
+
+    let (radix, digits) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (16, hex)
+    } else if let Some(binary) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (2, binary)
+    } else {
+        (10, digits)
+    };
+
+    // from_str_radix would accept a second sign
+    if digits.starts_with('+') || digits.starts_with('-') {
+        return None;
+    }
+
+    let value = i64::from_str_radix(digits, radix).ok()?;
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Tokens;
+
+    const SCHEMA: &[ArgSpec] = &[
+        ArgSpec::required("channel", ArgKind::U8),
+        ArgSpec::required("freq", ArgKind::int(1, 1000)),
+        ArgSpec::optional("mode", ArgKind::Enum(&["on", "off"])),
+    ];
+
+    fn check(line: &str) -> Result<(), ArgError> {
+        let tokens = Tokens::<64>::parse(line).unwrap();
+        match_args(SCHEMA, &tokens.args(0))
+            .map(|_| ())
+            .map_err(|message| message.error)
+    }
+
+    const OPTIONS: &[ArgSpec] = &[
+        ArgSpec::flag(Some('v'), "verbose"),
+        ArgSpec::flag(Some('x'), "extra"),
+        ArgSpec::flag(None, "force"),
+        ArgSpec::option(Some('n'), "count", ArgKind::U16),
+        ArgSpec::required("target", ArgKind::Str),
+    ];
+
+    fn with_options(line: &str, test: fn(Result<Args<'_>, ArgErrorMessage<'_>>)) {
+        let tokens = Tokens::<64>::parse(line).unwrap();
+        let args = tokens.args(0);
+        match match_args(OPTIONS, &args) {
+            Ok(matches) => test(Ok(args.with_matches(OPTIONS, &matches))),
+            Err(message) => test(Err(message)),
+        }
+    }
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(parse_int("42"), Some(42));
+        assert_eq!(parse_int("-42"), Some(-42));
+        assert_eq!(parse_int("0x2A"), Some(42));
+        assert_eq!(parse_int("0b101010"), Some(42));
+        assert_eq!(parse_int("--42"), None);
+        assert_eq!(parse_int("0x"), None);
+        assert_eq!(parse_int("4two"), None);
+        assert_eq!(u8::from_arg("256"), None);
+        assert_eq!(i8::from_arg("-128"), Some(-128));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(check("1 500"), Ok(()));
+        assert_eq!(check("0x01 1000 off"), Ok(()));
+        assert_eq!(
+            check("1 1001"),
+            Err(ArgError {
+                index: 2,
+                reason: ArgErrorReason::Invalid
+            })
+        );
+        assert_eq!(
+            check("1"),
+            Err(ArgError {
+                index: 2,
+                reason: ArgErrorReason::Missing
+            })
+        );
+        assert_eq!(
+            check("1 2 on extra"),
+            Err(ArgError {
+                index: 4,
+                reason: ArgErrorReason::Unexpected
+            })
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        with_options("-xv -n 5 foo", |args| {
+            let args = args.ok().unwrap();
+            assert!(args.has_flag('v'));
+            assert!(args.has_flag("extra"));
+            assert!(!args.has_flag("force"));
+            assert_eq!(args.opt::<u16>("count"), Some(5));
+            assert_eq!(args.value::<&str>("target"), Some("foo"));
+            assert_eq!(args.len(), 1);
+        });
+
+        with_options("--count=7 foo --force -n3", |args| {
+            let args = args.ok().unwrap();
+            assert!(args.has_flag("force"));
+            assert_eq!(args.opt::<u16>('n'), Some(3));
+            assert_eq!(args.get(0), Some("foo"));
+        });
+
+        with_options("-vn9 -- -x", |args| {
+            let args = args.ok().unwrap();
+            assert!(args.has_flag('v'));
+            assert!(!args.has_flag('x'));
+            assert_eq!(args.opt::<u16>("count"), Some(9));
+            assert_eq!(args.value::<&str>("target"), Some("-x"));
+        });
+
+        with_options("-5", |args| {
+            assert_eq!(args.ok().unwrap().get(0), Some("-5"));
+        });
+    }
+
+    #[test]
+    fn test_option_errors() {
+        with_options("-vq foo", |args| {
+            let message = args.err().unwrap();
+            assert_eq!(message.error.reason, ArgErrorReason::UnknownOption);
+            assert_eq!(std::format!("{}", message), "arg 1: unknown option '-vq'");
+        });
+
+        with_options("foo --count", |args| {
+            let message = args.err().unwrap();
+            assert_eq!(message.error.index, 2);
+            assert_eq!(
+                std::format!("{}", message),
+                "arg 2 '--count': missing value"
+            );
+        });
+
+        with_options("--count=lots foo", |args| {
+            let message = args.err().unwrap();
+            assert_eq!(
+                std::format!("{}", message),
+                "arg 1 '--count': expected integer 0..=65535"
+            );
+        });
+
+        with_options("--verbose=1 foo", |args| {
+            let message = args.err().unwrap();
+            assert_eq!(
+                std::format!("{}", message),
+                "arg 1: unexpected value '--verbose=1'"
+            );
+        });
+
+        with_options("-v", |args| {
+            let message = args.err().unwrap();
+            assert_eq!(message.error.reason, ArgErrorReason::Missing);
+            assert_eq!(std::format!("{}", message), "arg 2 'target': missing");
+        });
+    }
+
+    #[test]
+    fn test_error_message() {
+        let message = ArgErrorMessage {
+            error: ArgError {
+                index: 2,
+                reason: ArgErrorReason::Invalid,
+            },
+            spec: SCHEMA.get(1),
+            arg: Some("0"),
+        };
+        assert_eq!(
+            std::format!("{}", message),
+            "arg 2 'freq': expected integer 1..=1000"
+        );
+
+        let message = ArgErrorMessage {
+            error: ArgError {
+                index: 3,
+                reason: ArgErrorReason::Invalid,
+            },
+            spec: SCHEMA.get(2),
+            arg: Some("toggle"),
+        };
+        assert_eq!(
+            std::format!("{}", message),
+            "arg 3 'mode': expected one of on|off"
+        );
+    }
+
+    #[test]
+    fn test_complete_arg() {
+        const SCHEMA: &[ArgSpec] = &[
+            ArgSpec::flag(Some('v'), "verbose"),
+            ArgSpec::option(Some('m'), "mode", ArgKind::Enum(&["fast", "slow"])),
+            ArgSpec::required("pin", ArgKind::Enum(&["pa0", "pa1", "pb0"])),
+            ArgSpec::required("level", ArgKind::Bool),
+        ];
+
+        fn complete(line: &str) -> std::vec::Vec<std::string::String> {
+            let tokens = Tokens::<64>::parse(line).unwrap();
+            let args = tokens.args(0);
+            let prefix = if line.ends_with(' ') || line.is_empty() {
+                ""
+            } else {
+                args.get(args.len() - 1).unwrap()
+            };
+            let index = args.len() - usize::from(!prefix.is_empty());
+
+            let mut completions = Completions::new(prefix);
+            complete_arg(SCHEMA, &args, index, &mut completions);
+            completions.iter().map(|c| c.into()).collect()
+        }
+
+        assert_eq!(complete(""), ["pa0", "pa1", "pb0"]);
+        assert_eq!(complete("pa"), ["pa0", "pa1"]);
+        assert_eq!(complete("-v pb"), ["pb0"]);
+        assert!(complete("pa0 ").is_empty());
+        assert_eq!(complete("--"), ["--verbose", "--mode"]);
+        assert_eq!(complete("pa0 --m"), ["--mode"]);
+        assert_eq!(complete("--mode "), ["fast", "slow"]);
+        assert_eq!(complete("-vm s"), ["slow"]);
+        assert_eq!(complete("-m fast "), ["pa0", "pa1", "pb0"]);
+        assert!(complete("-mfast -").is_empty());
+    }
+}
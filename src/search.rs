@@ -0,0 +1,133 @@
+use core::fmt::{self, Write};
+
+use heapless::String;
+
+/// State of a reverse incremental search through the history, as started
+/// by Ctrl+R. History entries are numbered oldest first.
+pub(crate) struct Search<const N: usize> {
+    query: String<N>,
+    /// The line as it was before the search, put back if it is aborted
+    original: String<N>,
+    /// The entry last found to contain the query
+    found: Option<usize>,
+    /// No entry contains the query as it is now
+    failed: bool,
+}
+
+impl<const N: usize> Search<N> {
+    pub(crate) fn new(original: &str) -> Search<N> {
+        let mut search = Search {
+            query: String::new(),
+            original: String::new(),
+            found: None,
+            failed: false,
+        };
+        // Cannot fail, the line is no longer than the search's buffers
+        let _ = search.original.push_str(original);
+
+        search
+    }
+
+    pub(crate) fn original(&self) -> &str {
+        &self.original
+    }
+
+    pub(crate) fn found(&self) -> Option<usize> {
+        self.found
+    }
+
+    /// Adds to the query, keeping the entry found if it still matches
+    pub(crate) fn push<'h>(&mut self, c: char, history: impl Iterator<Item = &'h str>) {
+        if self.query.push(c).is_ok() {
+            let below = self.found.map_or(usize::MAX, |found| found + 1);
+            self.find(history, below);
+        }
+    }
+
+    /// Removes the end of the query and searches again from the most
+    /// recent entry
+    pub(crate) fn pop<'h>(&mut self, history: impl Iterator<Item = &'h str>) {
+        if self.query.pop().is_some() {
+            self.find(history, usize::MAX);
+        }
+    }
+
+    /// Moves on to the next older entry containing the query
+    pub(crate) fn older<'h>(&mut self, history: impl Iterator<Item = &'h str>) {
+        self.find(history, self.found.unwrap_or(usize::MAX));
+    }
+
+    /// Draws the search over the prompt, showing `entry` as the match
+    pub(crate) fn draw(&self, entry: Option<&str>, out: &mut dyn Write) -> fmt::Result {
+        write!(
+            out,
+            "\r\x1B[K({}reverse-i-search)'{}': {}",
+            if self.failed { "failed " } else { "" },
+            self.query,
+            entry.unwrap_or("")
+        )
+    }
+
+    /// Finds the most recent entry before `below` that contains the query
+    fn find<'h>(&mut self, history: impl Iterator<Item = &'h str>, below: usize) {
+        let query = self.query.as_str();
+        let found = history
+            .enumerate()
+            .take_while(|&(index, _)| index < below)
+            .filter(|(_, entry)| entry.contains(query))
+            .last();
+
+        match found {
+            Some((index, _)) => {
+                self.found = Some(index);
+                self.failed = false;
+            }
+            None => self.failed = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: [&str; 4] = ["dump regs", "led on", "dump mem 0 16", "reset"];
+
+    fn draw(search: &Search<32>) -> std::string::String {
+        let mut out = std::string::String::new();
+        let entry = search.found().map(|index| HISTORY[index]);
+        search.draw(entry, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_search() {
+        let mut search = Search::<32>::new("le");
+        assert_eq!(draw(&search), "\r\x1b[K(reverse-i-search)'': ");
+
+        search.push('d', HISTORY.into_iter());
+        assert_eq!(search.found(), Some(2));
+        search.push('u', HISTORY.into_iter());
+        search.push('m', HISTORY.into_iter());
+        assert_eq!(
+            draw(&search),
+            "\r\x1b[K(reverse-i-search)'dum': dump mem 0 16"
+        );
+
+        search.older(HISTORY.into_iter());
+        assert_eq!(search.found(), Some(0));
+
+        // Nothing older, the last match stays
+        search.older(HISTORY.into_iter());
+        assert_eq!(
+            draw(&search),
+            "\r\x1b[K(failed reverse-i-search)'dum': dump regs"
+        );
+
+        search.push('x', HISTORY.into_iter());
+        assert_eq!(search.found(), Some(0));
+        search.pop(HISTORY.into_iter());
+        assert_eq!(search.found(), Some(2));
+        assert_eq!(search.original(), "le");
+    }
+}
@@ -0,0 +1,146 @@
+use core::fmt;
+
+#[cfg(feature = "embedded-hal-02")]
+use embedded_hal::serial::Write;
+
+#[cfg(feature = "async")]
+use crate::CliError;
+
+/// Writes text to a function taking it a byte at a time, to use as the
+/// output of [`Cli::feed`](crate::Cli::feed) when it goes to a byte sink
+/// rather than to a [`core::fmt::Write`]
+///
+/// ```ignore
+/// cli.feed(byte, &mut ByteSink(|b| tx_queue.enqueue(b).unwrap_or(())))?;
+/// ```
+pub struct ByteSink<F: FnMut(u8)>(pub F);
+
+impl<F: FnMut(u8)> fmt::Write for ByteSink<F> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(&mut self.0);
+        Ok(())
+    }
+}
+
+/// [`core::fmt::Write`] over the [`Write<u8>`] half of a serial port,
+/// blocking on each byte
+#[cfg(feature = "embedded-hal-02")]
+pub(crate) struct SerialWriter<'w, W> {
+    tx: &'w mut W,
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<'w, W: Write<u8>> SerialWriter<'w, W> {
+    pub(crate) fn new(tx: &'w mut W) -> SerialWriter<'w, W> {
+        SerialWriter { tx }
+    }
+}
+
+#[cfg(feature = "embedded-hal-02")]
+impl<W: Write<u8>> fmt::Write for SerialWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes()
+            .try_for_each(|byte| nb::block!(self.tx.write(byte)))
+            .map_err(|_| fmt::Error)
+    }
+}
+
+/// Gathers the output [`Cli::run_async`](crate::Cli::run_async) writes
+/// while handling a byte, to be written out by awaiting
+/// [`AsyncWriter::write_to`]. Output past `N` bytes is dropped and fails the
+/// write.
+#[cfg(feature = "async")]
+pub(crate) struct AsyncWriter<const N: usize> {
+    buf: heapless::Vec<u8, N>,
+    overflowed: bool,
+}
+
+#[cfg(feature = "async")]
+impl<const N: usize> AsyncWriter<N> {
+    pub(crate) fn new() -> AsyncWriter<N> {
+        AsyncWriter {
+            buf: heapless::Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Writes out the output gathered, failing if some of it did not fit
+    pub(crate) async fn write_to<W: embedded_io_async::Write>(
+        &mut self,
+        tx: &mut W,
+    ) -> Result<(), CliError> {
+        let written = tx.write_all(&self.buf).await;
+        self.buf.clear();
+
+        match (written, core::mem::take(&mut self.overflowed)) {
+            (Ok(()), false) => Ok(()),
+            _ => Err(CliError::WriteError),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<const N: usize> fmt::Write for AsyncWriter<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.overflowed || self.buf.extend_from_slice(s.as_bytes()).is_err() {
+            self.overflowed = true;
+            return Err(fmt::Error);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write as _;
+
+    #[test]
+    fn test_byte_sink() {
+        let mut bytes = std::vec::Vec::new();
+        let prompt = "é>";
+        write!(ByteSink(|b| bytes.push(b)), "{}", prompt).unwrap();
+        assert_eq!(bytes, [0xC3, 0xA9, b'>']);
+    }
+
+    #[cfg(feature = "embedded-hal-02")]
+    /// Takes a byte every other call, and fails once full
+    struct Tx {
+        bytes: heapless::Vec<u8, 4>,
+        ready: bool,
+    }
+
+    #[cfg(feature = "embedded-hal-02")]
+    impl Write<u8> for Tx {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            self.ready = !self.ready;
+            match self.ready {
+                true => self.bytes.push(byte).map_err(|_| nb::Error::Other(())),
+                false => Err(nb::Error::WouldBlock),
+            }
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-hal-02")]
+    #[test]
+    fn test_serial_writer() {
+        let mut tx = Tx {
+            bytes: heapless::Vec::new(),
+            ready: false,
+        };
+
+        {
+            let mut writer = SerialWriter::new(&mut tx);
+            assert_eq!(writer.write_str("cli>"), Ok(()));
+            assert_eq!(writer.write_str(" "), Err(fmt::Error));
+        }
+        assert_eq!(tx.bytes.as_slice(), b"cli>");
+    }
+}
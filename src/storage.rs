@@ -0,0 +1,596 @@
+use core::ops::Range;
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use heapless::{HistoryBuffer, String};
+
+use crate::CliError;
+
+/// Starts every record
+const MAGIC: [u8; 4] = *b"CLIH";
+
+/// Magic, sequence number, payload length and CRC of the sequence number,
+/// length and payload
+const HEADER_SIZE: usize = 14;
+
+/// Flash is read and written through a buffer of this size, so the read
+/// and write sizes of the flash must divide it
+const CHUNK_SIZE: usize = 64;
+
+/// Keeps the history in a region of NOR flash across resets, for
+/// [`Cli::init_with_history`](crate::Cli::init_with_history) and
+/// [`Cli::save_history`](crate::Cli::save_history).
+///
+/// The region is split into slots, each big enough for a whole history.
+/// Every save writes a record to the slot after the one last written, and
+/// a sector is only erased when a save reaches its first slot, so the wear
+/// is spread over the whole region. Records carry a sequence number and a
+/// CRC: the newest intact record is the one loaded, and corrupt data, for
+/// example from a save cut short by a reset, is ignored.
+///
+/// The region must start and end on sector boundaries and hold at least two
+/// erase units: the sectors needed for one slot.
+pub struct HistoryStore<S> {
+    flash: S,
+    region: Range<u32>,
+    /// The slot to write next and the sequence number for it, once known
+    next: Option<(usize, u32)>,
+}
+
+/// Where the slots are in the region
+struct Layout {
+    slot_size: usize,
+    /// Sectors erased together, holding a whole number of slots
+    unit_size: usize,
+    slots_per_unit: usize,
+    slots: usize,
+}
+
+impl<S: NorFlash> HistoryStore<S> {
+    pub fn new(flash: S, region: Range<u32>) -> HistoryStore<S> {
+        HistoryStore {
+            flash,
+            region,
+            next: None,
+        }
+    }
+
+    /// Gives the flash back
+    pub fn release(self) -> S {
+        self.flash
+    }
+
+    /// Replaces the history with the newest record saved, if any
+    pub(crate) fn load<const LINE_SIZE: usize, const HISTORY_SIZE: usize>(
+        &mut self,
+        history: &mut HistoryBuffer<String<LINE_SIZE>, HISTORY_SIZE>,
+    ) -> Result<(), CliError> {
+        let layout = self.layout(record_size(LINE_SIZE, HISTORY_SIZE))?;
+        let newest = self.scan(&layout).map_err(storage_error)?;
+
+        self.next = Some(match newest {
+            Some((slot, seq)) => ((slot + 1) % layout.slots, seq.wrapping_add(1)),
+            None => (0, 0),
+        });
+
+        if let Some((slot, _)) = newest {
+            history.clear();
+
+            // Entries end with a line feed; any too long for the line are
+            // dropped rather than cut short
+            let mut entry: String<LINE_SIZE> = String::new();
+            let mut too_long = false;
+            self.read_record(&layout, slot, |byte| {
+                if byte == b'\n' {
+                    if !too_long {
+                        history.write(entry.clone());
+                    }
+                    entry.clear();
+                    too_long = false;
+                } else if entry.push(byte as char).is_err() {
+                    too_long = true;
+                }
+            })
+            .map_err(storage_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the history to the next slot
+    pub(crate) fn save<const LINE_SIZE: usize, const HISTORY_SIZE: usize>(
+        &mut self,
+        history: &HistoryBuffer<String<LINE_SIZE>, HISTORY_SIZE>,
+    ) -> Result<(), CliError> {
+        let layout = self.layout(record_size(LINE_SIZE, HISTORY_SIZE))?;
+        let (mut slot, seq) = match self.next {
+            Some(next) => next,
+            None => match self.scan(&layout).map_err(storage_error)? {
+                Some((slot, seq)) => ((slot + 1) % layout.slots, seq.wrapping_add(1)),
+                None => (0, 0),
+            },
+        };
+
+        // A slot left dirty by a save cut short cannot be written over, so
+        // move on to the next unit
+        if slot % layout.slots_per_unit != 0
+            && !self.is_erased(&layout, slot).map_err(storage_error)?
+        {
+            slot = (slot / layout.slots_per_unit + 1) * layout.slots_per_unit % layout.slots;
+        }
+
+        if slot % layout.slots_per_unit == 0 {
+            let start = layout.offset(&self.region, slot);
+            self.flash
+                .erase(start, start + layout.unit_size as u32)
+                .map_err(storage_error)?;
+        }
+
+        self.write_record(&layout, slot, seq, history)
+            .map_err(storage_error)?;
+        self.next = Some(((slot + 1) % layout.slots, seq.wrapping_add(1)));
+
+        Ok(())
+    }
+
+    fn layout(&self, record_size: usize) -> Result<Layout, CliError> {
+        let align = S::READ_SIZE.max(S::WRITE_SIZE);
+        let start = self.region.start as usize;
+        let size = self.region.end.saturating_sub(self.region.start) as usize;
+
+        if CHUNK_SIZE % align != 0 || start % S::ERASE_SIZE != 0 || size % S::ERASE_SIZE != 0 {
+            return Err(CliError::StorageError(NorFlashErrorKind::NotAligned));
+        }
+
+        let slot_size = record_size.next_multiple_of(align);
+        let unit_size = slot_size.next_multiple_of(S::ERASE_SIZE);
+        let slots_per_unit = unit_size / slot_size;
+        let units = size / unit_size;
+
+        if units < 2
+            || record_size - HEADER_SIZE > usize::from(u16::MAX)
+            || self.region.end as usize > self.flash.capacity()
+        {
+            return Err(CliError::StorageError(NorFlashErrorKind::OutOfBounds));
+        }
+
+        Ok(Layout {
+            slot_size,
+            unit_size,
+            slots_per_unit,
+            slots: units * slots_per_unit,
+        })
+    }
+
+    /// Finds the slot holding the newest intact record, with its sequence
+    /// number
+    fn scan(&mut self, layout: &Layout) -> Result<Option<(usize, u32)>, S::Error> {
+        let mut newest: Option<(usize, u32)> = None;
+
+        for slot in 0..layout.slots {
+            if let Some(seq) = self.read_record(layout, slot, |_| ())? {
+                if newest.map_or(true, |(_, newest)| seq > newest) {
+                    newest = Some((slot, seq));
+                }
+            }
+        }
+
+        Ok(newest)
+    }
+
+    /// Reads the record in `slot`, handing each byte of its payload to `f`.
+    /// Returns the record's sequence number, or `None` if the slot does not
+    /// hold an intact record.
+    fn read_record(
+        &mut self,
+        layout: &Layout,
+        slot: usize,
+        mut f: impl FnMut(u8),
+    ) -> Result<Option<u32>, S::Error> {
+        let start = layout.offset(&self.region, slot);
+        let mut reader = SlotReader::new(&mut self.flash, start, layout.slot_size);
+
+        if reader.bytes::<4>()? != MAGIC {
+            return Ok(None);
+        }
+        let seq = reader.bytes::<4>()?;
+        let len = reader.bytes::<2>()?;
+        let crc = u32::from_le_bytes(reader.bytes::<4>()?);
+
+        let payload = usize::from(u16::from_le_bytes(len));
+        if HEADER_SIZE + payload > layout.slot_size {
+            return Ok(None);
+        }
+
+        let mut check = Crc::new();
+        check.update(&seq);
+        check.update(&len);
+        for _ in 0..payload {
+            let byte = reader.byte()?;
+            check.update(&[byte]);
+            f(byte);
+        }
+
+        Ok((check.finish() == crc).then(|| u32::from_le_bytes(seq)))
+    }
+
+    fn write_record<const LINE_SIZE: usize, const HISTORY_SIZE: usize>(
+        &mut self,
+        layout: &Layout,
+        slot: usize,
+        seq: u32,
+        history: &HistoryBuffer<String<LINE_SIZE>, HISTORY_SIZE>,
+    ) -> Result<(), S::Error> {
+        let payload = || {
+            history
+                .oldest_ordered()
+                .flat_map(|entry| entry.bytes().chain([b'\n']))
+        };
+
+        let seq = seq.to_le_bytes();
+        // Fits, the layout checks the largest payload does
+        let len = (payload().count() as u16).to_le_bytes();
+
+        let mut crc = Crc::new();
+        crc.update(&seq);
+        crc.update(&len);
+        for byte in payload() {
+            crc.update(&[byte]);
+        }
+
+        let start = layout.offset(&self.region, slot);
+        let mut writer = ChunkWriter::new(&mut self.flash, start);
+        for byte in MAGIC
+            .into_iter()
+            .chain(seq)
+            .chain(len)
+            .chain(crc.finish().to_le_bytes())
+            .chain(payload())
+        {
+            writer.byte(byte)?;
+        }
+
+        writer.finish()
+    }
+
+    fn is_erased(&mut self, layout: &Layout, slot: usize) -> Result<bool, S::Error> {
+        let start = layout.offset(&self.region, slot);
+        let mut reader = SlotReader::new(&mut self.flash, start, layout.slot_size);
+
+        for _ in 0..layout.slot_size {
+            if reader.byte()? != 0xFF {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Layout {
+    fn offset(&self, region: &Range<u32>, slot: usize) -> u32 {
+        let unit = slot / self.slots_per_unit;
+        let index = slot % self.slots_per_unit;
+
+        region.start + (unit * self.unit_size + index * self.slot_size) as u32
+    }
+}
+
+/// Size of a record holding a full history: every entry is followed by a
+/// line feed
+fn record_size(line_size: usize, history_size: usize) -> usize {
+    HEADER_SIZE + history_size * (line_size + 1)
+}
+
+fn storage_error(error: impl NorFlashError) -> CliError {
+    CliError::StorageError(error.kind())
+}
+
+/// Reads a slot a byte at a time through a buffer
+struct SlotReader<'f, S> {
+    flash: &'f mut S,
+    offset: u32,
+    /// Bytes of the slot not read into the buffer yet
+    left: usize,
+    chunk: [u8; CHUNK_SIZE],
+    pos: usize,
+    len: usize,
+}
+
+impl<'f, S: ReadNorFlash> SlotReader<'f, S> {
+    fn new(flash: &'f mut S, offset: u32, size: usize) -> SlotReader<'f, S> {
+        SlotReader {
+            flash,
+            offset,
+            left: size,
+            chunk: [0; CHUNK_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// The next byte. The caller never reads past the end of the slot.
+    fn byte(&mut self) -> Result<u8, S::Error> {
+        if self.pos == self.len {
+            let len = CHUNK_SIZE.min(self.left);
+            self.flash.read(self.offset, &mut self.chunk[..len])?;
+            self.offset += len as u32;
+            self.left -= len;
+            self.pos = 0;
+            self.len = len;
+        }
+
+        let byte = self.chunk[self.pos];
+        self.pos += 1;
+
+        Ok(byte)
+    }
+
+    fn bytes<const M: usize>(&mut self) -> Result<[u8; M], S::Error> {
+        let mut bytes = [0; M];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte()?;
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Writes a record a byte at a time through a buffer
+struct ChunkWriter<'f, S> {
+    flash: &'f mut S,
+    offset: u32,
+    chunk: [u8; CHUNK_SIZE],
+    len: usize,
+}
+
+impl<'f, S: NorFlash> ChunkWriter<'f, S> {
+    fn new(flash: &'f mut S, offset: u32) -> ChunkWriter<'f, S> {
+        ChunkWriter {
+            flash,
+            offset,
+            chunk: [0; CHUNK_SIZE],
+            len: 0,
+        }
+    }
+
+    fn byte(&mut self, byte: u8) -> Result<(), S::Error> {
+        self.chunk[self.len] = byte;
+        self.len += 1;
+
+        if self.len == CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes what is left, padded to the write size as erased flash
+    fn finish(mut self) -> Result<(), S::Error> {
+        while self.len % S::WRITE_SIZE != 0 {
+            self.chunk[self.len] = 0xFF;
+            self.len += 1;
+        }
+
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), S::Error> {
+        if self.len > 0 {
+            self.flash.write(self.offset, &self.chunk[..self.len])?;
+            self.offset += self.len as u32;
+            self.len = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// CRC-32 as used by Ethernet and zlib
+struct Crc(u32);
+
+impl Crc {
+    fn new() -> Crc {
+        Crc(!0)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// NOR flash in RAM, to try a [`HistoryStore`] out on the host: erasing
+/// sets bytes to 0xFF and writing can only clear bits, as on the real
+/// thing. It reads a byte at a time, writes 4 bytes at a time and erases
+/// sectors of 256 bytes, so `SIZE` is a whole number of sectors.
+///
+/// It starts out zeroed rather than erased, like flash holding something
+/// else, so a store over it has to erase before its first save.
+///
+/// ```ignore
+/// let mut store = HistoryStore::new(RamFlash::<1024>::new(), 0..1024);
+/// cli.save_history(&mut store)?;
+/// cli.init_with_history(&mut out, &mut store)?;
+/// ```
+pub struct RamFlash<const SIZE: usize> {
+    data: [u8; SIZE],
+    erases: usize,
+}
+
+impl<const SIZE: usize> RamFlash<SIZE> {
+    pub const fn new() -> RamFlash<SIZE> {
+        RamFlash {
+            data: [0; SIZE],
+            erases: 0,
+        }
+    }
+
+    /// The contents of the flash, to corrupt a record with for example
+    pub fn data_mut(&mut self) -> &mut [u8; SIZE] {
+        &mut self.data
+    }
+
+    /// The number of times sectors were erased, one per call to
+    /// [`NorFlash::erase`]
+    pub fn erases(&self) -> usize {
+        self.erases
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), NorFlashErrorKind> {
+        if (offset as usize) % align != 0 || len % align != 0 {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        if offset as usize + len > SIZE {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> Default for RamFlash<SIZE> {
+    fn default() -> RamFlash<SIZE> {
+        RamFlash::new()
+    }
+}
+
+impl<const SIZE: usize> ErrorType for RamFlash<SIZE> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<const SIZE: usize> ReadNorFlash for RamFlash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for RamFlash<SIZE> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 256;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check(from, to.saturating_sub(from) as usize, Self::ERASE_SIZE)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+        self.erases += 1;
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        for (data, byte) in self.data[offset as usize..].iter_mut().zip(bytes) {
+            *data &= byte;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type RamFlash = super::RamFlash<1024>;
+
+    fn history(lines: &[&str]) -> HistoryBuffer<String<32>, 2> {
+        let mut history = HistoryBuffer::new();
+        for &line in lines {
+            history.write(String::from(line));
+        }
+        history
+    }
+
+    fn load(flash: RamFlash) -> (std::vec::Vec<std::string::String>, RamFlash) {
+        let mut store = HistoryStore::new(flash, 0..1024);
+        let mut loaded = history(&[]);
+        store.load(&mut loaded).unwrap();
+
+        let lines = loaded.oldest_ordered().map(|l| l.to_string()).collect();
+        (lines, store.release())
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut crc = Crc::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut store = HistoryStore::new(RamFlash::new(), 0..1024);
+        let mut empty = history(&[]);
+        store.load(&mut empty).unwrap();
+        assert!(empty.is_empty());
+
+        store.save(&history(&["led on", "dump regs"])).unwrap();
+        store.save(&history(&["dump regs", "reset"])).unwrap();
+
+        let (lines, _) = load(store.release());
+        assert_eq!(lines, ["dump regs", "reset"]);
+    }
+
+    #[test]
+    fn test_wear_and_corruption() {
+        // Slots of 84 bytes, three to a 256 byte sector, four sectors
+        let mut store = HistoryStore::new(RamFlash::new(), 0..1024);
+        for n in 0..20 {
+            let line = std::format!("pwm {}", n);
+            store.save(&history(&["led on", &line])).unwrap();
+        }
+
+        let mut flash = store.release();
+        assert_eq!(flash.erases(), 7);
+
+        let (lines, flash_after) = load(flash);
+        assert_eq!(lines, ["led on", "pwm 19"]);
+        flash = flash_after;
+
+        // Save 19 is in slot 7, the second of the third sector
+        flash.data_mut()[256 * 2 + 84 + HEADER_SIZE] ^= 1;
+        let (lines, flash) = load(flash);
+        assert_eq!(lines, ["led on", "pwm 18"]);
+
+        // The next save skips the corrupt slot's sector
+        let mut store = HistoryStore::new(flash, 0..1024);
+        store.save(&history(&["reset"])).unwrap();
+        let (lines, _) = load(store.release());
+        assert_eq!(lines, ["reset"]);
+    }
+
+    #[test]
+    fn test_layout() {
+        let store = HistoryStore::new(RamFlash::new(), 0..256);
+        assert!(matches!(
+            store.layout(record_size(32, 2)),
+            Err(CliError::StorageError(NorFlashErrorKind::OutOfBounds))
+        ));
+
+        let store = HistoryStore::new(RamFlash::new(), 128..1024);
+        assert!(matches!(
+            store.layout(record_size(32, 2)),
+            Err(CliError::StorageError(NorFlashErrorKind::NotAligned))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+/// Interpret As Command, starting every telnet command
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const NAWS: u8 = 31;
+
+/// What a byte from the client turned out to be
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    /// Input for the `Cli`
+    Input(u8),
+    /// Part of a command, or a line ending already passed on
+    Nothing,
+    /// An option the client asked for was refused, with this answer to send
+    /// back
+    Reply([u8; 3]),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    /// After `\r`, where the client sends `\n` or `\0` to go with it
+    CarriageReturn,
+    Iac,
+    /// After WILL, WONT, DO or DONT, waiting for the option
+    Option(u8),
+    /// In a subnegotiation, and whether the last byte was IAC
+    Sub {
+        iac: bool,
+    },
+}
+
+/// The server side of a telnet connection. Takes the bytes from the client
+/// apart, leaving the input for the `Cli` and keeping the window size.
+///
+/// ```ignore
+/// socket.write_all(Telnet::NEGOTIATION)?;
+/// for byte in received {
+///     match telnet.receive(byte) {
+///         Received::Input(byte) => _ = cli.feed(byte, &mut out),
+///         Received::Reply(reply) => socket.write_all(&reply)?,
+///         Received::Nothing => (),
+///     }
+/// }
+/// ```
+pub struct Telnet {
+    state: State,
+    /// The option being subnegotiated, once named
+    sub_option: Option<u8>,
+    /// The start of the subnegotiation's data, enough for the window size
+    sub_data: [u8; 4],
+    sub_len: usize,
+    window_size: Option<(u16, u16)>,
+}
+
+impl Telnet {
+    /// What the server offers once a client connects, to be sent first: it
+    /// echoes and suppresses go-ahead, so that the client sends keys as
+    /// they are pressed, and it asks for the window size
+    pub const NEGOTIATION: &'static [u8] =
+        &[IAC, WILL, ECHO, IAC, WILL, SUPPRESS_GO_AHEAD, IAC, DO, NAWS];
+
+    pub const fn new() -> Telnet {
+        Telnet {
+            state: State::Data,
+            sub_option: None,
+            sub_data: [0; 4],
+            sub_len: 0,
+            window_size: None,
+        }
+    }
+
+    /// `bytes` as they are sent to a telnet client, with 0xFF doubled so
+    /// that it is not taken as a command
+    pub fn escape(bytes: &[u8]) -> impl Iterator<Item = u8> + '_ {
+        bytes
+            .iter()
+            .flat_map(|&byte| core::iter::repeat(byte).take(if byte == IAC { 2 } else { 1 }))
+    }
+
+    /// The width and height of the client's window, once it has told
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    pub fn receive(&mut self, byte: u8) -> Received {
+        let (state, received) = match (self.state, byte) {
+            (State::CarriageReturn, b'\n' | b'\0') => (State::Data, Received::Nothing),
+            (State::Data | State::CarriageReturn, IAC) => (State::Iac, Received::Nothing),
+            (State::Data | State::CarriageReturn, b'\r') => {
+                (State::CarriageReturn, Received::Input(byte))
+            }
+            (State::Data | State::CarriageReturn, _) => (State::Data, Received::Input(byte)),
+
+            // An escaped 0xFF is data
+            (State::Iac, IAC) => (State::Data, Received::Input(IAC)),
+            (State::Iac, WILL | WONT | DO | DONT) => (State::Option(byte), Received::Nothing),
+            (State::Iac, SB) => {
+                self.sub_option = None;
+                self.sub_len = 0;
+                (State::Sub { iac: false }, Received::Nothing)
+            }
+            // Go-ahead, no-operation and the like are ignored
+            (State::Iac, _) => (State::Data, Received::Nothing),
+
+            (State::Option(verb), option) => (State::Data, Self::answer(verb, option)),
+
+            (State::Sub { iac: true }, SE) => {
+                self.end_sub();
+                (State::Data, Received::Nothing)
+            }
+            (State::Sub { iac: false }, IAC) => (State::Sub { iac: true }, Received::Nothing),
+            (State::Sub { .. }, _) => {
+                self.push_sub(byte);
+                (State::Sub { iac: false }, Received::Nothing)
+            }
+        };
+
+        self.state = state;
+        received
+    }
+
+    /// Refuses the options other than those offered in
+    /// [`Telnet::NEGOTIATION`]. Answers to the offers are expected and left
+    /// unanswered, as are refusals, so the negotiation cannot loop.
+    fn answer(verb: u8, option: u8) -> Received {
+        match (verb, option) {
+            (DO, ECHO | SUPPRESS_GO_AHEAD) | (WILL, NAWS) | (WONT | DONT, _) => Received::Nothing,
+            (DO, _) => Received::Reply([IAC, WONT, option]),
+            _ => Received::Reply([IAC, DONT, option]),
+        }
+    }
+
+    fn push_sub(&mut self, byte: u8) {
+        if self.sub_option.is_none() {
+            self.sub_option = Some(byte);
+        } else if let Some(slot) = self.sub_data.get_mut(self.sub_len) {
+            *slot = byte;
+            self.sub_len += 1;
+        }
+    }
+
+    fn end_sub(&mut self) {
+        if let (Some(NAWS), 4) = (self.sub_option, self.sub_len) {
+            let [w1, w0, h1, h0] = self.sub_data;
+            self.window_size = Some((u16::from_be_bytes([w1, w0]), u16::from_be_bytes([h1, h0])));
+        }
+    }
+}
+
+impl Default for Telnet {
+    fn default() -> Telnet {
+        Telnet::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(telnet: &mut Telnet, bytes: &[u8]) -> std::vec::Vec<u8> {
+        bytes
+            .iter()
+            .filter_map(|&byte| match telnet.receive(byte) {
+                Received::Input(byte) => Some(byte),
+                Received::Nothing => None,
+                Received::Reply(reply) => panic!("unexpected reply {:?}", reply),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_input() {
+        let mut telnet = Telnet::new();
+
+        // Commands are taken out, and `\r\n` or `\r\0` is one Enter
+        assert_eq!(
+            input(&mut telnet, b"le\xff\xf1d\r\0ls\r\n\r\xff\xff"),
+            b"led\rls\r\r\xff"
+        );
+        assert_eq!(input(&mut telnet, b"\xff\xfd\x01\xff\xfb\x1f"), b"");
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let mut telnet = Telnet::new();
+
+        // Terminal type is refused
+        assert_eq!(input(&mut telnet, &[IAC, DO]), b"");
+        assert_eq!(telnet.receive(24), Received::Reply([IAC, WONT, 24]));
+        assert_eq!(input(&mut telnet, &[IAC, WILL]), b"");
+        assert_eq!(telnet.receive(24), Received::Reply([IAC, DONT, 24]));
+        assert_eq!(input(&mut telnet, &[IAC, DONT, 24, IAC, WONT, 24]), b"");
+
+        // A width of 255 is escaped
+        assert_eq!(telnet.window_size(), None);
+        assert_eq!(
+            input(
+                &mut telnet,
+                &[IAC, SB, NAWS, 0, IAC, IAC, 0, 24, IAC, SE, b'x']
+            ),
+            b"x"
+        );
+        assert_eq!(telnet.window_size(), Some((255, 24)));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert!(Telnet::escape(b"cli> ").eq(*b"cli> "));
+        assert!(Telnet::escape(&[1, IAC, 2]).eq([1, IAC, IAC, 2]));
+    }
+}
@@ -0,0 +1,228 @@
+use core::fmt::{self, Write};
+
+use crate::keymap::Action;
+use crate::line::{LineBuffer, Motion};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Insert,
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+}
+
+/// State of the vi editing mode: the current mode and an operator (`d` or
+/// `c`) waiting for its motion
+pub(crate) struct ViState {
+    pub(crate) mode: Mode,
+    pending: Option<Operator>,
+}
+
+impl ViState {
+    pub(crate) const fn new() -> ViState {
+        ViState {
+            mode: Mode::Insert,
+            pending: None,
+        }
+    }
+
+    /// Applies the vi specific part of an action. Returns `false` if the
+    /// action is left to the `Cli`.
+    pub(crate) fn apply<const N: usize>(
+        &mut self,
+        action: Action,
+        line: &mut LineBuffer<N>,
+        out: &mut dyn Write,
+    ) -> Result<bool, fmt::Error> {
+        if self.mode == Mode::Insert {
+            if action != Action::ViNormalMode {
+                return Ok(false);
+            }
+
+            self.mode = Mode::Normal;
+            line.move_by(Motion::Left, out)?;
+            return Ok(true);
+        }
+
+        if let Some(operator) = self.pending.take() {
+            self.operate(operator, action, line, out)?;
+            return Ok(true);
+        }
+
+        match action {
+            Action::ViInsert => self.mode = Mode::Insert,
+            Action::ViInsertStart => {
+                line.move_by(Motion::Home, out)?;
+                self.mode = Mode::Insert;
+            }
+            Action::ViAppend => {
+                line.move_by(Motion::Right, out)?;
+                self.mode = Mode::Insert;
+            }
+            Action::ViAppendEnd => {
+                line.move_by(Motion::End, out)?;
+                self.mode = Mode::Insert;
+            }
+            Action::ViNextWord => line.move_by(Motion::NextWord, out)?,
+            Action::ViDelete => self.pending = Some(Operator::Delete),
+            Action::ViChange => self.pending = Some(Operator::Change),
+            // Typing does nothing in normal mode
+            Action::Insert(_) | Action::ViNormalMode => (),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Keeps the cursor on a character in normal mode, as vi does
+    pub(crate) fn settle<const N: usize>(
+        &self,
+        line: &mut LineBuffer<N>,
+        out: &mut dyn Write,
+    ) -> fmt::Result {
+        if self.mode == Mode::Normal && !line.is_empty() && line.at_end() {
+            line.move_by(Motion::Left, out)?;
+        }
+
+        Ok(())
+    }
+
+    fn operate<const N: usize>(
+        &mut self,
+        operator: Operator,
+        action: Action,
+        line: &mut LineBuffer<N>,
+        out: &mut dyn Write,
+    ) -> fmt::Result {
+        let motion = match action {
+            // `dd` and `cc` work on the whole line
+            Action::ViDelete | Action::ViChange => {
+                line.move_by(Motion::Home, out)?;
+                Motion::End
+            }
+            // `cw` changes up to the end of the word, like `ce`
+            Action::ViNextWord if operator == Operator::Change => Motion::WordEnd,
+            Action::ViNextWord => Motion::NextWord,
+            Action::Left => Motion::Left,
+            Action::Right => Motion::Right,
+            Action::Home => Motion::Home,
+            Action::End => Motion::End,
+            Action::WordLeft => Motion::WordStart,
+            Action::WordRight => Motion::WordEnd,
+            // Anything else cancels the operator
+            _ => return Ok(()),
+        };
+
+        line.kill_by(motion, out)?;
+        if operator == Operator::Change {
+            self.mode = Mode::Insert;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(vi: &mut ViState, line: &mut LineBuffer<32>, actions: &[Action]) {
+        let mut out = std::string::String::new();
+        for &action in actions {
+            if !vi.apply(action, line, &mut out).unwrap() {
+                match action {
+                    Action::Insert(c) => {
+                        line.insert(c, &mut out).unwrap();
+                    }
+                    Action::Home => line.move_by(Motion::Home, &mut out).unwrap(),
+                    Action::Right => line.move_by(Motion::Right, &mut out).unwrap(),
+                    Action::Delete => line.delete(&mut out).unwrap(),
+                    _ => unreachable!(),
+                }
+            }
+            vi.settle(line, &mut out).unwrap();
+        }
+    }
+
+    fn typed(text: &str) -> std::vec::Vec<Action> {
+        text.chars().map(Action::Insert).collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut vi = ViState::new();
+        let mut line = LineBuffer::<32>::new();
+
+        apply(&mut vi, &mut line, &typed("led on"));
+        apply(
+            &mut vi,
+            &mut line,
+            &[Action::ViNormalMode, Action::Insert('x')],
+        );
+        assert_eq!(vi.mode, Mode::Normal);
+        assert_eq!(line.as_str(), "led on");
+
+        // x on the last character, then append
+        apply(&mut vi, &mut line, &[Action::Delete, Action::Delete]);
+        assert_eq!(line.as_str(), "led ");
+        apply(&mut vi, &mut line, &[Action::ViAppendEnd]);
+        apply(&mut vi, &mut line, &typed("off"));
+        assert_eq!(vi.mode, Mode::Insert);
+        assert_eq!(line.as_str(), "led off");
+
+        apply(
+            &mut vi,
+            &mut line,
+            &[Action::ViNormalMode, Action::ViInsertStart],
+        );
+        apply(&mut vi, &mut line, &typed("#"));
+        assert_eq!(line.as_str(), "#led off");
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut vi = ViState::new();
+        let mut line = LineBuffer::<32>::new();
+
+        apply(&mut vi, &mut line, &typed("pwm set 1 50"));
+        apply(&mut vi, &mut line, &[Action::ViNormalMode, Action::Home]);
+
+        // dw
+        apply(
+            &mut vi,
+            &mut line,
+            &[Action::ViNextWord, Action::ViDelete, Action::ViNextWord],
+        );
+        assert_eq!(line.as_str(), "pwm 1 50");
+
+        // cw keeps the space after the word
+        apply(&mut vi, &mut line, &[Action::ViChange, Action::ViNextWord]);
+        assert_eq!(vi.mode, Mode::Insert);
+        apply(&mut vi, &mut line, &typed("2"));
+        assert_eq!(line.as_str(), "pwm 2 50");
+
+        // d$, then an operator cancelled by a non motion
+        apply(
+            &mut vi,
+            &mut line,
+            &[
+                Action::ViNormalMode,
+                Action::Right,
+                Action::ViDelete,
+                Action::End,
+            ],
+        );
+        assert_eq!(line.as_str(), "pwm 2");
+        apply(&mut vi, &mut line, &[Action::ViDelete, Action::ViInsert]);
+        assert_eq!(vi.mode, Mode::Normal);
+
+        // cc
+        apply(&mut vi, &mut line, &[Action::ViChange, Action::ViChange]);
+        assert_eq!(line.as_str(), "");
+        assert_eq!(vi.mode, Mode::Insert);
+    }
+}